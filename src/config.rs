@@ -0,0 +1,283 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "disk-history")]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use rust_decimal::Decimal;
+use crate::model::{Account, AccountKey, ClientId, StandardAmountParser, TxEvent, TxId};
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessorConfig {
+    pub sanitize_amounts: Option<AmountSanitizer>,
+    /// Turns the (sanitized) amount cell into a `Decimal`.
+    pub amount_parser: SharedAmountParser,
+    pub on_account_final: Option<AccountCallback>,
+    /// Checked before each event is applied; returning true locks the account and skips the event,
+    /// which the tx history keeps as frozen so a replay locks the account too.
+    pub freeze_rule: Option<FreezeRule>,
+    /// Treat a repeated (type, tx) pair as a redelivery and skip it. Only deliveries that applied
+    /// count, so one that failed is applied when it comes again. A redelivered stream cannot be
+    /// told apart from a genuine second dispute, so this overrides the rule that a resolved tx can be
+    /// disputed again: only the first dispute and the first resolve of each tx are applied.
+    pub idempotent: bool,
+    pub withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    pub deposit_dispute_policy: DepositDisputePolicy,
+    /// Stop reading after this many records.
+    pub max_rows: Option<usize>,
+    /// Roughly how many accounts the input will open, to size the account map up front instead of
+    /// growing it while reading.
+    pub expected_clients: Option<usize>,
+    /// Only the most recent N deposits/withdrawals of each client may be disputed; a dispute of an
+    /// older one is skipped and reported through `error_sink` without failing the row. This does
+    /// not bound memory: the tx history still keeps every deposit and withdrawal, since resolves,
+    /// chargebacks, `verify_against_history` and `explain_tx` read it.
+    pub dispute_window: Option<usize>,
+    /// Record bad rows in `Processor::errors` and keep going instead of aborting on the first one.
+    pub continue_on_error: bool,
+    /// Where rows skipped under `continue_on_error` are reported.
+    pub error_sink: ErrorSink,
+    /// Report whitespace-only lines as bad rows instead of skipping them.
+    pub blank_lines_as_errors: bool,
+    /// Fail the whole file if any row was bad, even with `continue_on_error`, so no output is produced
+    /// from a partially applied file. The first row error is returned once the file has been read.
+    pub atomic: bool,
+    /// Maximum records read per second.
+    pub rate_limit: Option<u32>,
+    /// Retry a read failing with `Interrupted` or `WouldBlock` this many times before giving up.
+    pub read_retries: usize,
+    /// Wait before the first retry, doubled for each one after it.
+    pub read_retry_backoff: Duration,
+    /// Stop reading once processing has run this long, keeping the rows applied so far and setting
+    /// `Processor::timed_out`. With `atomic` the file fails instead.
+    pub timeout: Option<Duration>,
+    /// Read the whole input and parse it as this many chunks in parallel; rows are still applied in file order.
+    pub parse_chunks: Option<usize>,
+    /// Accept rows with fewer or more fields than the header, e.g. disputes without a trailing amount
+    /// column. Missing trailing fields read as empty.
+    pub flexible: bool,
+    /// Fail any row whose `type`, `client`, `tx` or `amount` field is empty or missing, even a
+    /// dispute's, or whose field count differs from the header's, to catch truncated rows in
+    /// fixed-schema files. Takes precedence over `flexible`.
+    pub require_all_fields: bool,
+    /// Reject files whose header isn't `type, client, tx, amount`, followed by any of the optional
+    /// `currency`, `to_client` and `timestamp` in that order.
+    pub strict_header: bool,
+    /// With `strict_header`, accept the expected columns in any order.
+    pub header_any_order: bool,
+    /// Reject files whose header has a column this parser doesn't know, such as `memo`, with
+    /// `ProcessorError::UnknownColumn`. Otherwise such columns are ignored.
+    pub reject_unknown_columns: bool,
+    pub empty_amount_as: EmptyAmount,
+    /// Amounts on deposit and withdrawal rows are signed: a negative amount is a withdrawal of its
+    /// absolute value and any other a deposit, whichever of the two the type column says.
+    pub signed_amounts: bool,
+    /// Read amounts written with non-ASCII decimal digits, such as fullwidth `５．２５` or
+    /// Arabic-Indic `٥٫٢٥`, as their ASCII equivalents.
+    pub unicode_digits: bool,
+    /// Let a dispute row's amount hold only part of the original transaction.
+    pub partial_disputes: bool,
+    /// Fail dispute, resolve and chargeback rows that carry an amount instead of ignoring it. Dispute
+    /// amounts are still accepted with `partial_disputes`.
+    pub reject_unexpected_amount: bool,
+    /// Report a dispute for a tx that is already under dispute as `ProcessorError::AlreadyDisputed`
+    /// through `error_sink` rather than only warning. The dispute is a no-op either way: the account
+    /// is left untouched and the row does not fail, so the file is processed to the end.
+    pub report_redundant_disputes: bool,
+    /// Fail, with `ProcessorError::TooManyDisputes`, any dispute once the client has had this many
+    /// disputes applied, counting every currency and disputes later resolved.
+    pub max_disputes_per_client: Option<usize>,
+    /// Fail any deposit or withdrawal whose tx id is not above the last one of the same client that
+    /// applied; a row that failed or was frozen does not count.
+    pub require_monotonic_tx: bool,
+    /// Let resolves and chargebacks settle disputes on a locked account, so held funds are not
+    /// stranded by an earlier chargeback. New disputes, deposits and withdrawals are unaffected.
+    pub allow_resolve_on_locked: bool,
+    /// Debited from available by every chargeback, in the same step that locks the account, so the
+    /// fee is charged even though the account is locked afterwards. Available may go negative.
+    pub chargeback_fee: Option<Decimal>,
+    /// Reject, as a row error, a chargeback that would leave the account's total below zero, e.g. on a
+    /// deposit whose funds were already withdrawn. The account and the dispute are left as they were.
+    pub reject_negative_total: bool,
+    /// Reject, as a row error, any deposit that would take an account's available balance above this.
+    pub max_balance: Option<Decimal>,
+    /// Skip, like an overdraw, any withdrawal or transfer that would leave less than this available.
+    pub min_balance: Option<Decimal>,
+    /// Check every event against its account's total and record any unexpected decrease in
+    /// `Processor::invariant_violations`.
+    pub strict_invariants: bool,
+    /// Record each applied event with its account's resulting balances in `Processor::trace`.
+    pub trace: bool,
+    /// Record how each applied event changed its account in `Processor::deltas`.
+    pub record_deltas: bool,
+    /// Once processing ends, record in `Processor::held_ratio_alerts` every account holding more than
+    /// this multiple of its available funds.
+    pub max_held_ratio: Option<Decimal>,
+    pub locked_behavior: LockedBehavior,
+    /// Only process rows for these clients.
+    pub include_clients: Option<HashSet<ClientId>>,
+    /// Skip rows for these clients.
+    pub exclude_clients: HashSet<ClientId>,
+    #[cfg(feature = "disk-history")]
+    pub history_path: Option<PathBuf>
+}
+
+impl ProcessorConfig {
+    /// Whether `include_clients` and `exclude_clients` let rows for `client` through.
+    pub fn selects_client(&self, client: ClientId) -> bool {
+        self.include_clients.as_ref().is_none_or(|include| include.contains(&client))
+            && !self.exclude_clients.contains(&client)
+    }
+}
+
+/// How a dispute against a withdrawal moves funds. `Mirror` reverses a deposit dispute and can
+/// leave held negative; `HoldCredit` holds the withdrawn amount as a pending credit instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    #[default]
+    Mirror,
+    HoldCredit
+}
+
+/// How much of a disputed deposit is held. `CapAtAvailable` never takes available below zero;
+/// the part that could not be held is recorded as the dispute's shortfall.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DepositDisputePolicy {
+    #[default]
+    HoldFull,
+    CapAtAvailable
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorSink {
+    /// Logged at error level, which the binary's logger prints to stderr.
+    Stderr,
+    /// Kept in `Processor::errors`.
+    #[default]
+    Collect,
+    Both,
+    Silent
+}
+
+/// Treatment of deposits and withdrawals that arrive for a locked account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockedBehavior {
+    /// Deposits are still credited and withdrawals are dropped.
+    #[default]
+    DepositsOnly,
+    Drop,
+    /// Set aside in `Processor::deferred` without touching the account.
+    Defer,
+    /// Reported as a row error.
+    Reject
+}
+
+/// Treatment of a deposit or withdrawal row whose amount cell is empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyAmount {
+    #[default]
+    Error,
+    Zero,
+    Skip
+}
+
+#[derive(Debug, Clone)]
+pub struct AmountSanitizer {
+    pub currency_symbols: Vec<char>,
+    pub thousands_separator: char
+}
+
+impl Default for AmountSanitizer {
+    fn default() -> AmountSanitizer {
+        AmountSanitizer {
+            currency_symbols: vec!['$', '€', '£'],
+            thousands_separator: ','
+        }
+    }
+}
+
+impl AmountSanitizer {
+    /// Drops currency symbols, and thousands separators as long as they group the integer part in
+    /// threes; `1,00` or `1.234,56` would otherwise turn into a different amount. `tx` is only for
+    /// error messages.
+    pub fn sanitize(&self, raw: &str, tx: TxId) -> Result<String, Box<dyn Error>> {
+        let cleaned: String = raw.chars().filter(|c| !self.currency_symbols.contains(c)).collect();
+        let cleaned = cleaned.trim();
+        let unsigned = cleaned.trim_start_matches(['-', '+']);
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if integer.contains(self.thousands_separator) {
+            let mut groups = integer.split(self.thousands_separator);
+            let first = groups.next().unwrap_or_default().chars().count();
+            let grouped = (1..=3).contains(&first) && groups.all(|group| group.chars().count() == 3);
+            if !grouped {
+                return Err(From::from(format!("Invalid amount {:?} for tx {}", raw, tx)));
+            }
+        }
+        if fraction.contains(self.thousands_separator) {
+            return Err(From::from(format!("Invalid amount {:?} for tx {}", raw, tx)));
+        }
+        Ok(cleaned.chars().filter(|c| *c != self.thousands_separator).collect())
+    }
+}
+
+/// Parses an amount cell; `tx` is only for error messages.
+pub trait AmountParser: Send + Sync {
+    fn parse(&self, raw: &str, tx: TxId) -> Result<Decimal, Box<dyn Error>>;
+}
+
+#[derive(Clone)]
+pub struct SharedAmountParser(Arc<dyn AmountParser>);
+
+impl SharedAmountParser {
+    pub fn new<P: AmountParser + 'static>(parser: P) -> SharedAmountParser {
+        SharedAmountParser(Arc::new(parser))
+    }
+    pub fn parse(&self, raw: &str, tx: TxId) -> Result<Decimal, Box<dyn Error>> { self.0.parse(raw, tx) }
+}
+
+impl Default for SharedAmountParser {
+    fn default() -> SharedAmountParser { SharedAmountParser::new(StandardAmountParser) }
+}
+
+impl fmt::Debug for SharedAmountParser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedAmountParser")
+    }
+}
+
+#[derive(Clone)]
+pub struct AccountCallback(Arc<dyn Fn(AccountKey, Account) + Send + Sync>);
+
+impl AccountCallback {
+    pub fn new<F>(callback: F) -> AccountCallback
+    where F: Fn(AccountKey, Account) + Send + Sync + 'static {
+        AccountCallback(Arc::new(callback))
+    }
+    pub fn call(&self, key: AccountKey, account: Account) { (self.0)(key, account) }
+}
+
+impl fmt::Debug for AccountCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccountCallback")
+    }
+}
+
+type FreezeFn = dyn Fn(&Account, &TxEvent) -> bool + Send + Sync;
+
+#[derive(Clone)]
+pub struct FreezeRule(Arc<FreezeFn>);
+
+impl FreezeRule {
+    pub fn new<F>(rule: F) -> FreezeRule
+    where F: Fn(&Account, &TxEvent) -> bool + Send + Sync + 'static {
+        FreezeRule(Arc::new(rule))
+    }
+    pub fn call(&self, account: &Account, event: &TxEvent) -> bool { (self.0)(account, event) }
+}
+
+impl fmt::Debug for FreezeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FreezeRule")
+    }
+}
@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use crate::model::{ClientId, TxId, TxKindRaw};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RowError {
+    pub line: u64,
+    pub tx: Option<TxId>,
+    pub kind: Option<TxKindRaw>,
+    pub message: String,
+    /// Input bytes skipped over when the csv reader could not parse the record at all.
+    #[serde(skip)]
+    pub bytes: Option<Range<u64>>
+}
+
+impl RowError {
+    pub fn new<E: fmt::Display>(line: u64, tx: Option<TxId>, kind: Option<TxKindRaw>, err: E) -> RowError {
+        RowError {
+            line,
+            tx,
+            kind,
+            message: err.to_string(),
+            bytes: None
+        }
+    }
+    pub fn with_bytes(mut self, bytes: Range<u64>) -> RowError {
+        self.bytes = Some(bytes);
+        self
+    }
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for RowError {}
+
+/// An event that lowered its account's total although only withdrawals and chargebacks may.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvariantViolation {
+    pub tx: TxId,
+    pub kind: TxKindRaw,
+    pub client: ClientId,
+    pub before: Decimal,
+    pub after: Decimal
+}
+
+/// An account whose held funds exceed `max_held_ratio` times its available funds once processing ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldRatioAlert {
+    pub client: ClientId,
+    pub currency: Option<String>,
+    pub available: Decimal,
+    pub held: Decimal
+}
+
+#[derive(Debug)]
+pub enum ProcessorError {
+    BadHeader { found: Vec<String> },
+    PrecisionLoss { tx: TxId, amount: String },
+    /// A record on `line` is not valid UTF-8.
+    InvalidEncoding { line: u64 },
+    /// A dispute, resolve or chargeback row carried an amount under `reject_unexpected_amount`.
+    UnexpectedAmount { tx: TxId },
+    /// A dispute for a tx already under dispute, under `report_redundant_disputes`.
+    AlreadyDisputed { tx: TxId },
+    /// A dispute for a tx that is no longer among its client's last `window` deposits and
+    /// withdrawals, under `dispute_window`.
+    OutsideDisputeWindow { tx: TxId, window: usize },
+    /// A deposit or withdrawal whose tx id is not above the client's previous one, under
+    /// `require_monotonic_tx`.
+    NonMonotonicTx { tx: TxId, previous: TxId },
+    /// A dispute beyond the client's `max_disputes_per_client`.
+    TooManyDisputes { client: ClientId, max: usize },
+    /// A row with an empty or missing required field, under `require_all_fields`.
+    MissingField { column: String },
+    /// A row with more or fewer fields than the header has columns, under `require_all_fields`.
+    FieldCount { expected: usize, found: usize },
+    /// A header column that is neither required nor optional, under `reject_unknown_columns`.
+    UnknownColumn { name: String },
+    Io(io::Error),
+    Csv(csv::Error)
+}
+
+impl fmt::Display for ProcessorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessorError::BadHeader { found } => {
+                write!(f, "expected header \"type, client, tx, amount\", but got \"{}\"", found.join(", "))
+            }
+            ProcessorError::PrecisionLoss { tx, amount } => {
+                write!(f, "Amount {:?} for tx {} has more digits than a Decimal can hold", amount, tx)
+            }
+            ProcessorError::InvalidEncoding { line } => {
+                write!(f, "Invalid UTF-8 on line {}; the input must be UTF-8 encoded", line)
+            }
+            ProcessorError::UnexpectedAmount { tx } => {
+                write!(f, "Unexpected amount on tx {}; disputes, resolves and chargebacks take no amount", tx)
+            }
+            ProcessorError::AlreadyDisputed { tx } => write!(f, "Tx {} is already under dispute", tx),
+            ProcessorError::OutsideDisputeWindow { tx, window } => {
+                write!(f, "Tx {} is older than the client's last {} transactions and can no longer be disputed", tx, window)
+            }
+            ProcessorError::NonMonotonicTx { tx, previous } => {
+                write!(f, "Tx {} is out of order; the client's previous tx was {}", tx, previous)
+            }
+            ProcessorError::TooManyDisputes { client, max } => {
+                write!(f, "Client {} has already opened the maximum of {} disputes", client, max)
+            }
+            ProcessorError::MissingField { column } => write!(f, "Row has no {} field", column),
+            ProcessorError::FieldCount { expected, found } => {
+                write!(f, "Row has {} fields; expected exactly {}", found, expected)
+            }
+            ProcessorError::UnknownColumn { name } => {
+                write!(f, "Unknown column {:?}; expected type, client, tx, amount, currency, to_client or timestamp", name)
+            }
+            ProcessorError::Io(err) => write!(f, "{}", err),
+            ProcessorError::Csv(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl Error for ProcessorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProcessorError::BadHeader { .. }
+            | ProcessorError::PrecisionLoss { .. }
+            | ProcessorError::InvalidEncoding { .. }
+            | ProcessorError::UnexpectedAmount { .. }
+            | ProcessorError::AlreadyDisputed { .. }
+            | ProcessorError::OutsideDisputeWindow { .. }
+            | ProcessorError::NonMonotonicTx { .. }
+            | ProcessorError::TooManyDisputes { .. }
+            | ProcessorError::MissingField { .. }
+            | ProcessorError::FieldCount { .. }
+            | ProcessorError::UnknownColumn { .. } => None,
+            ProcessorError::Io(err) => Some(err),
+            ProcessorError::Csv(err) => Some(err)
+        }
+    }
+}
+
+impl From<io::Error> for ProcessorError {
+    fn from(err: io::Error) -> ProcessorError { ProcessorError::Io(err) }
+}
+
+impl From<csv::Error> for ProcessorError {
+    fn from(err: csv::Error) -> ProcessorError {
+        match err.kind() {
+            csv::ErrorKind::Utf8 { pos, .. } => {
+                ProcessorError::InvalidEncoding { line: pos.as_ref().map_or(0, |pos| pos.line()) }
+            }
+            _ => ProcessorError::Csv(err)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorReportFormat {
+    #[default]
+    Csv,
+    Json
+}
+
+#[cfg(test)]
+mod processor_error_tests {
+    use std::error::Error;
+    use std::io;
+    use crate::error::ProcessorError;
+
+    #[test]
+    fn test_from_io_error() {
+        let err: ProcessorError = io::Error::new(io::ErrorKind::NotFound, "missing").into();
+        assert!(matches!(&err, ProcessorError::Io(inner) if inner.kind() == io::ErrorKind::NotFound));
+        assert_eq!(err.to_string(), "missing");
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_from_csv_error() {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader("a,b\nc".as_bytes());
+        let csv_err = rdr.records().find_map(|record| record.err()).unwrap();
+        let err: ProcessorError = csv_err.into();
+        assert!(matches!(&err, ProcessorError::Csv(inner) if matches!(inner.kind(), csv::ErrorKind::UnequalLengths { .. })));
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_from_utf8_csv_error() {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(&b"a,b\nc,\xff\n"[..]);
+        let csv_err = rdr.records().find_map(|record| record.err()).unwrap();
+        let err: ProcessorError = csv_err.into();
+        assert!(matches!(err, ProcessorError::InvalidEncoding { line: 2 }));
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn test_question_mark_conversion() {
+        fn open_missing() -> Result<(), ProcessorError> {
+            std::fs::File::open("src/transaction_test_data/no_file_found.csv")?;
+            Ok(())
+        }
+        assert!(matches!(open_missing(), Err(ProcessorError::Io(_))));
+    }
+}
@@ -0,0 +1,281 @@
+use std::error::Error;
+use dashmap::DashMap;
+use crate::config::ProcessorConfig;
+use crate::model::{TxId, TxRecord};
+
+/// Storage for deposits and withdrawals, looked up again by disputes, resolves and chargebacks.
+pub struct TxHistory {
+    store: Store
+}
+
+enum Store {
+    Memory(DashMap<TxId, TxRecord>),
+    #[cfg(feature = "disk-history")]
+    Disk(disk::DiskStore)
+}
+
+impl TxHistory {
+    pub fn in_memory() -> TxHistory {
+        TxHistory { store: Store::Memory(DashMap::new()) }
+    }
+    #[cfg(feature = "disk-history")]
+    pub fn on_disk(path: &std::path::Path) -> Result<TxHistory, Box<dyn Error>> {
+        Ok(TxHistory { store: Store::Disk(disk::DiskStore::create(path)?) })
+    }
+    #[allow(unused_variables)]
+    pub fn from_config(config: &ProcessorConfig) -> Result<TxHistory, Box<dyn Error>> {
+        #[cfg(feature = "disk-history")]
+        if let Some(path) = &config.history_path {
+            return TxHistory::on_disk(path);
+        }
+        Ok(TxHistory::in_memory())
+    }
+    pub fn get(&self, tx: TxId) -> Result<Option<TxRecord>, Box<dyn Error>> {
+        match &self.store {
+            Store::Memory(map) => Ok(map.get(&tx).map(|record| record.clone())),
+            #[cfg(feature = "disk-history")]
+            Store::Disk(store) => store.get(tx)
+        }
+    }
+    /// Inserts a new record or overwrites the record already stored for `tx`.
+    pub fn insert(&self, tx: TxId, record: TxRecord) -> Result<(), Box<dyn Error>> {
+        match &self.store {
+            Store::Memory(map) => {
+                map.insert(tx, record);
+                Ok(())
+            }
+            #[cfg(feature = "disk-history")]
+            Store::Disk(store) => store.insert(tx, &record)
+        }
+    }
+    pub fn records(&self) -> Result<Vec<(TxId, TxRecord)>, Box<dyn Error>> {
+        match &self.store {
+            Store::Memory(map) => Ok(map.iter().map(|record| (*record.key(), record.value().clone())).collect()),
+            #[cfg(feature = "disk-history")]
+            Store::Disk(store) => store.records()
+        }
+    }
+    pub fn len(&self) -> usize {
+        match &self.store {
+            Store::Memory(map) => map.len(),
+            #[cfg(feature = "disk-history")]
+            Store::Disk(store) => store.len()
+        }
+    }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+#[cfg(feature = "disk-history")]
+mod disk {
+    use std::error::Error;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use dashmap::DashMap;
+    use rust_decimal::Decimal;
+    use crate::model::{RecordKind, Timestamp, TxId, TxRecord};
+
+    const CURRENCY_LEN: usize = 8;
+    const TIMESTAMP_AT: usize = 39 + CURRENCY_LEN;
+    const LINE_AT: usize = TIMESTAMP_AT + 13;
+    const TO_CLIENT_AT: usize = LINE_AT + 8;
+    const FROZEN_AT: usize = TO_CLIENT_AT + 3;
+    const RECORD_LEN: usize = FROZEN_AT + 1;
+
+    /// Fixed-width records appended to a file, with only the tx id to offset index kept in memory.
+    pub struct DiskStore {
+        file: Mutex<File>,
+        index: DashMap<TxId, u64>
+    }
+
+    impl DiskStore {
+        pub fn create(path: &Path) -> Result<DiskStore, Box<dyn Error>> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            Ok(DiskStore { file: Mutex::new(file), index: DashMap::new() })
+        }
+        pub fn get(&self, tx: TxId) -> Result<Option<TxRecord>, Box<dyn Error>> {
+            let offset = match self.index.get(&tx) {
+                None => return Ok(None),
+                Some(offset) => *offset
+            };
+            let mut buf = [0u8; RECORD_LEN];
+            let mut file = self.file.lock().map_err(|_| "tx history file lock poisoned")?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buf)?;
+            Ok(Some(decode(&buf)))
+        }
+        pub fn insert(&self, tx: TxId, record: &TxRecord) -> Result<(), Box<dyn Error>> {
+            let mut file = self.file.lock().map_err(|_| "tx history file lock poisoned")?;
+            let offset = match self.index.get(&tx) {
+                Some(offset) => file.seek(SeekFrom::Start(*offset))?,
+                None => file.seek(SeekFrom::End(0))?
+            };
+            file.write_all(&encode(record)?)?;
+            self.index.insert(tx, offset);
+            Ok(())
+        }
+        pub fn records(&self) -> Result<Vec<(TxId, TxRecord)>, Box<dyn Error>> {
+            let txs: Vec<TxId> = self.index.iter().map(|entry| *entry.key()).collect();
+            let mut records = Vec::with_capacity(txs.len());
+            for tx in txs {
+                if let Some(record) = self.get(tx)? {
+                    records.push((tx, record));
+                }
+            }
+            Ok(records)
+        }
+        pub fn len(&self) -> usize { self.index.len() }
+    }
+
+    fn encode(record: &TxRecord) -> Result<[u8; RECORD_LEN], Box<dyn Error>> {
+        let mut buf = [0u8; RECORD_LEN];
+        buf[0..2].copy_from_slice(&record.client().to_le_bytes());
+        buf[2..18].copy_from_slice(&record.amount().serialize());
+        buf[18] = *record.disputed() as u8;
+        buf[19] = *record.charged_back() as u8;
+        buf[20] = match record.kind() {
+            RecordKind::Deposit => 0,
+            RecordKind::Withdrawal => 1
+        };
+        buf[21] = *record.rejected() as u8;
+        buf[22..38].copy_from_slice(&record.disputed_amount().serialize());
+        if let Some(currency) = record.currency() {
+            let bytes = currency.as_bytes();
+            if bytes.len() > CURRENCY_LEN {
+                return Err(From::from(format!("Currency code {:?} is too long for the tx history file", currency)));
+            }
+            buf[38] = bytes.len() as u8 + 1;
+            buf[39..39 + bytes.len()].copy_from_slice(bytes);
+        }
+        if let Some(at) = record.timestamp() {
+            buf[TIMESTAMP_AT] = 1;
+            buf[TIMESTAMP_AT + 1..TIMESTAMP_AT + 9].copy_from_slice(&at.secs().to_le_bytes());
+            buf[TIMESTAMP_AT + 9..LINE_AT].copy_from_slice(&at.nanos().to_le_bytes());
+        }
+        // lines are 1-based, so 0 stands for none
+        buf[LINE_AT..TO_CLIENT_AT].copy_from_slice(&record.line().unwrap_or(0).to_le_bytes());
+        if let Some(to_client) = record.to_client() {
+            buf[TO_CLIENT_AT] = 1;
+            buf[TO_CLIENT_AT + 1..FROZEN_AT].copy_from_slice(&to_client.to_le_bytes());
+        }
+        buf[FROZEN_AT] = *record.frozen() as u8;
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8; RECORD_LEN]) -> TxRecord {
+        let mut amount = [0u8; 16];
+        amount.copy_from_slice(&buf[2..18]);
+        let kind = match buf[20] {
+            0 => RecordKind::Deposit,
+            _ => RecordKind::Withdrawal
+        };
+        let mut disputed_amount = [0u8; 16];
+        disputed_amount.copy_from_slice(&buf[22..38]);
+        let currency = match buf[38] {
+            0 => None,
+            len => Some(String::from_utf8_lossy(&buf[39..38 + len as usize]).into_owned())
+        };
+        let timestamp = match buf[TIMESTAMP_AT] {
+            0 => None,
+            _ => {
+                let mut secs = [0u8; 8];
+                secs.copy_from_slice(&buf[TIMESTAMP_AT + 1..TIMESTAMP_AT + 9]);
+                let mut nanos = [0u8; 4];
+                nanos.copy_from_slice(&buf[TIMESTAMP_AT + 9..LINE_AT]);
+                Some(Timestamp::from_unix(i64::from_le_bytes(secs), u32::from_le_bytes(nanos)))
+            }
+        };
+        let mut line = [0u8; 8];
+        line.copy_from_slice(&buf[LINE_AT..TO_CLIENT_AT]);
+        let line = match u64::from_le_bytes(line) {
+            0 => None,
+            line => Some(line)
+        };
+        let mut record = TxRecord::new(
+            u16::from_le_bytes([buf[0], buf[1]]),
+            Decimal::deserialize(amount),
+            false,
+            kind
+        ).with_currency(currency).with_timestamp(timestamp).with_line(line);
+        if buf[TO_CLIENT_AT] == 1 {
+            record = record.with_to_client(Some(u16::from_le_bytes([buf[TO_CLIENT_AT + 1], buf[TO_CLIENT_AT + 2]])));
+        }
+        if buf[18] == 1 {
+            record.hold_partial(&Decimal::deserialize(disputed_amount));
+        }
+        if buf[19] == 1 {
+            record.finish_chargeback();
+        }
+        if buf[21] == 1 {
+            record.reject();
+        }
+        if buf[FROZEN_AT] == 1 {
+            record.freeze();
+        }
+        record
+    }
+
+    #[cfg(test)]
+    mod disk_store_tests {
+        use rust_decimal::dec;
+        use crate::model::{RecordKind, Timestamp, TxRecord};
+        use super::{decode, encode};
+
+        #[test]
+        fn test_encode_decode_round_trip() {
+            let mut record = TxRecord::new(7, dec!(-12.3456), true, RecordKind::Withdrawal);
+            record.finish_chargeback();
+            record.reject();
+            record.freeze();
+            let decoded = decode(&encode(&record).unwrap());
+            assert_eq!(decoded.client(), &7);
+            assert_eq!(decoded.amount(), &dec!(-12.3456));
+            assert!(decoded.disputed());
+            assert!(decoded.charged_back());
+            assert_eq!(decoded.kind(), &RecordKind::Withdrawal);
+            assert_eq!(decoded.currency(), &None);
+            assert!(decoded.rejected());
+            assert_eq!(decoded.disputed_amount(), &dec!(-12.3456));
+            assert_eq!(decoded.timestamp(), &None);
+            assert_eq!(decoded.line(), &None);
+            assert_eq!(decoded.to_client(), &None);
+            assert!(decoded.frozen());
+        }
+
+        #[test]
+        fn test_encode_decode_transfer() {
+            let record = TxRecord::new(7, dec!(1), false, RecordKind::Withdrawal).with_to_client(Some(65535));
+            assert_eq!(decode(&encode(&record).unwrap()).to_client(), &Some(65535));
+        }
+
+        #[test]
+        fn test_encode_decode_timestamp() {
+            let at = Timestamp::from_unix(-1714564800, 999_999_999);
+            let record = TxRecord::new(7, dec!(1), false, RecordKind::Deposit).with_timestamp(Some(at)).with_line(Some(12));
+            let decoded = decode(&encode(&record).unwrap());
+            assert_eq!(decoded.timestamp(), &Some(at));
+            assert_eq!(decoded.line(), &Some(12));
+        }
+
+        #[test]
+        fn test_encode_decode_currency() {
+            let record = TxRecord::new(7, dec!(1), false, RecordKind::Deposit).with_currency(Some("EUR".to_string()));
+            let decoded = decode(&encode(&record).unwrap());
+            assert_eq!(decoded.currency(), &Some("EUR".to_string()));
+
+            let mut partial = TxRecord::new(7, dec!(10), false, RecordKind::Deposit);
+            partial.hold_partial(&dec!(4));
+            let decoded = decode(&encode(&partial).unwrap());
+            assert!(decoded.disputed());
+            assert_eq!(decoded.disputed_amount(), &dec!(4));
+            let too_long = TxRecord::new(7, dec!(1), false, RecordKind::Deposit).with_currency(Some("DOGECOINS".to_string()));
+            assert!(encode(&too_long).is_err());
+        }
+    }
+}
@@ -1,17 +1,89 @@
+pub mod config;
+pub mod error;
+pub mod history;
 pub mod model;
 pub mod processor;
 pub mod utils;
+#[cfg(test)]
+mod testutil;
 
-use std::{process};
+use std::{fs, process, thread};
+use std::io::{stdin, stdout};
+use std::path::Path;
+use crate::config::ProcessorConfig;
+use crate::model::TxId;
 use crate::processor::Processor;
-use crate::utils::{get_first_arg, print_account_data};
+#[cfg(feature = "glob")]
+use crate::utils::expand_glob;
+use crate::utils::{compare_read_modes, explain_tx, flag_value, get_first_arg, has_flag, init_stderr_logger, print_account_data, run_repl, save_account_data, stderr_log_level, OutputFormat, OutputOptions};
 
 fn main() {
+    if let Some(level) = stderr_log_level()
+        && let Err(err) = init_stderr_logger(level)
+    {
+        eprintln!("{}", err);
+    }
+    if has_flag("--compare-modes") {
+        compare_modes();
+    }
+    if has_flag("--repl") {
+        if let Err(err) = run_repl(stdin().lock(), stdout().lock(), &Processor::default()) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    let explain = flag_value("--explain").map(|tx| match tx.to_string_lossy().parse::<TxId>() {
+        Ok(tx) => tx,
+        Err(err) => {
+            eprintln!("--explain expects a tx id: {}", err);
+            process::exit(1);
+        }
+    });
     match get_first_arg() {
         Ok(file_path) => {
-            match Processor::process_file(file_path) {
+            let config = ProcessorConfig {
+                trace: has_flag("--trace"),
+                record_deltas: explain.is_some(),
+                ..Default::default()
+            };
+            #[cfg(feature = "glob")]
+            let processed = expand_glob(Path::new(&file_path)).and_then(|paths| Processor::process_files(&paths, config));
+            #[cfg(not(feature = "glob"))]
+            let processed = Processor::process_file_with_config(file_path, config);
+            match processed {
                 Ok(processor) => {
-                    if let Err(err) = print_account_data(processor) {
+                    if let Some(tx) = explain {
+                        match explain_tx(&processor, tx) {
+                            Ok(explanation) => print!("{}", explanation),
+                            Err(err) => {
+                                eprintln!("{}", err);
+                                process::exit(1);
+                            }
+                        }
+                        return;
+                    }
+                    let format = match flag_value("--format") {
+                        None => OutputFormat::Csv,
+                        Some(name) => match name.to_string_lossy().parse() {
+                            Ok(format) => format,
+                            Err(err) => {
+                                eprintln!("{}", err);
+                                process::exit(1);
+                            }
+                        }
+                    };
+                    let options = OutputOptions {
+                        summary: has_flag("--summary"),
+                        format,
+                        trace: has_flag("--trace"),
+                        ..Default::default()
+                    };
+                    let written = match flag_value("--output") {
+                        Some(path) => save_account_data(&processor, Path::new(&path), options),
+                        None => print_account_data(processor, options)
+                    };
+                    if let Err(err) = written {
                         eprintln!("{}", err);
                         process::exit(1);
                     }
@@ -30,3 +102,28 @@ fn main() {
 }
 
 
+
+/// Processes the input both serially and in parallel parse chunks, printing any rows that differ and
+/// exiting nonzero if there are some.
+fn compare_modes() -> ! {
+    let chunks = thread::available_parallelism().map_or(2, |n| n.get().max(2));
+    let differences = get_first_arg()
+        .and_then(|path| Ok(fs::read(path)?))
+        .and_then(|data| compare_read_modes(&data, chunks));
+    match differences {
+        Ok(differences) if differences.is_empty() => {
+            println!("serial and {}-chunk reads agree", chunks);
+            process::exit(0);
+        }
+        Ok(differences) => {
+            for (serial, chunked) in differences {
+                eprintln!("serial: {:?}\nchunked: {:?}", serial, chunked);
+            }
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
@@ -1,16 +1,139 @@
+use std::error::Error;
+use std::str::FromStr;
 use rust_decimal::{dec, Decimal};
 use serde::{Deserialize, Serialize};
+use crate::config::{AmountParser, DepositDisputePolicy, ProcessorConfig, WithdrawalDisputePolicy};
+use crate::error::ProcessorError;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
+pub struct TxRow {
+    #[serde(rename = "type")]
+    pub kind: TxKindRaw,
+    pub client: ClientId,
+    pub tx: TxId,
+    /// Defaulted so rows read in flexible mode may leave it off.
+    #[serde(default)]
+    pub amount: Option<String>,
+    #[serde(default)]
+    pub currency: Option<CurrencyCode>,
+    #[serde(default)]
+    pub to_client: Option<ClientId>,
+    #[serde(default)]
+    pub timestamp: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TxEvent {
     #[serde(rename = "type")]
     pub kind: TxKindRaw,
     pub client: ClientId,
     pub tx: TxId,
-    pub amount: Option<Decimal>
+    pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub currency: Option<CurrencyCode>,
+    /// Destination of a transfer.
+    #[serde(default)]
+    pub to_client: Option<ClientId>,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    /// Line of the input the event was read from.
+    #[serde(default)]
+    pub line: Option<u64>
+}
+
+impl TxEvent {
+    pub fn try_from_row(row: TxRow, config: &ProcessorConfig) -> Result<TxEvent, Box<dyn Error>> {
+        let amount = match row.amount {
+            None => None,
+            Some(raw) => {
+                let raw = match &config.sanitize_amounts {
+                    None => raw,
+                    Some(sanitizer) => sanitizer.sanitize(&raw, row.tx)?
+                };
+                let raw = if config.unicode_digits { ascii_digits(&raw) } else { raw };
+                Some(config.amount_parser.parse(&raw, row.tx)?)
+            }
+        };
+        if config.reject_unexpected_amount && amount.is_some() {
+            let allowed = row.kind == TxKindRaw::Dispute && config.partial_disputes;
+            if matches!(row.kind, TxKindRaw::Dispute | TxKindRaw::Resolve | TxKindRaw::Chargeback) && !allowed {
+                return Err(Box::new(ProcessorError::UnexpectedAmount { tx: row.tx }));
+            }
+        }
+        let (kind, amount) = match (row.kind, amount) {
+            (TxKindRaw::Deposit | TxKindRaw::Withdrawal, Some(amount)) if config.signed_amounts => {
+                let kind = if amount.is_sign_negative() { TxKindRaw::Withdrawal } else { TxKindRaw::Deposit };
+                (kind, Some(amount.abs()))
+            }
+            (kind, amount) => (kind, amount)
+        };
+        let timestamp = match row.timestamp {
+            None => None,
+            Some(raw) => Some(raw.parse::<Timestamp>().map_err(|err| format!("{} for tx {}", err, row.tx))?)
+        };
+        Ok(TxEvent {
+            kind,
+            client: row.client,
+            tx: row.tx,
+            amount,
+            currency: row.currency,
+            to_client: row.to_client,
+            timestamp,
+            line: None
+        })
+    }
 }
 
-#[derive(Debug, Default, Clone)]
+/// Plain or scientific decimal notation, refusing amounts a `Decimal` would have to round.
+pub struct StandardAmountParser;
+
+impl AmountParser for StandardAmountParser {
+    fn parse(&self, raw: &str, tx: TxId) -> Result<Decimal, Box<dyn Error>> {
+        check_precision(raw, tx)?;
+        let amount = Decimal::from_str(raw)
+            .or_else(|_| Decimal::from_scientific(raw))
+            .map_err(|_| format!("Invalid amount {:?} for tx {}", raw, tx))?;
+        Ok(amount)
+    }
+}
+
+/// The first code point of each run of ten Unicode decimal digits outside ASCII that amounts may use.
+const DIGIT_ZEROS: [u32; 20] = [
+    0x0660, 0x06F0, 0x07C0, 0x0966, 0x09E6, 0x0A66, 0x0AE6, 0x0B66, 0x0BE6, 0x0C66,
+    0x0CE6, 0x0D66, 0x0DE6, 0x0E50, 0x0ED0, 0x0F20, 0x1040, 0x17E0, 0x1810, 0xFF10,
+];
+
+/// Rewrites the digits in `DIGIT_ZEROS`, the Arabic decimal separator and the fullwidth full stop
+/// and minus as ASCII, leaving everything else as it was.
+fn ascii_digits(raw: &str) -> String {
+    raw.chars()
+        .map(|c| match c {
+            '\u{066B}' | '\u{FF0E}' => '.',
+            '\u{FF0D}' => '-',
+            _ => DIGIT_ZEROS.iter()
+                .find(|zero| (**zero..**zero + 10).contains(&u32::from(c)))
+                .and_then(|zero| char::from_digit(u32::from(c) - zero, 10))
+                .unwrap_or(c)
+        })
+        .collect()
+}
+
+/// `Decimal` holds 28 significant digits and 28 decimal places; longer amounts would parse but be
+/// silently rounded.
+fn check_precision(raw: &str, tx: TxId) -> Result<(), ProcessorError> {
+    const MAX_DIGITS: usize = 28;
+    let mantissa = raw.split(['e', 'E']).next().unwrap_or("");
+    let (int_part, frac_part) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let frac_part = frac_part.trim_end_matches('0');
+    let digits: String = int_part.chars().chain(frac_part.chars()).filter(|c| c.is_ascii_digit()).collect();
+    let significant = digits.trim_start_matches('0').len();
+    if significant > MAX_DIGITS || frac_part.len() > MAX_DIGITS {
+        return Err(ProcessorError::PrecisionLoss { tx, amount: raw.to_string() });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Account {
     available: Decimal,
     held:      Decimal,
@@ -18,11 +141,12 @@ pub struct Account {
 }
 
 impl Account {
-    pub fn new() -> Account {
+    pub fn new() -> Account { Account::default() }
+    pub fn from_parts(available: Decimal, held: Decimal, locked: bool) -> Account {
         Account {
-            available: dec!(0),
-            held: dec!(0),
-            locked: false
+            available,
+            held,
+            locked
         }
     }
     pub fn total(&self) -> Decimal { self.available + self.held }
@@ -39,41 +163,89 @@ impl Account {
         }
     }
     pub fn dispute_funds(&mut self, val: &Decimal, record_kind: &RecordKind) {
-        match record_kind {
-            RecordKind::Deposit => {
-                self.available -= val;
-                self.held += val
-            },
-            RecordKind::Withdrawal => {
-                self.available += val;
-                self.held -= val
-
-            }
+        self.apply_dispute(DisputeStep::Dispute, record_kind, val, WithdrawalDisputePolicy::Mirror)
+    }
+    /// The part of `val` a dispute would hold under `policy`; withdrawals are always held in full.
+    pub fn dispute_hold(&self, val: &Decimal, record_kind: &RecordKind, policy: DepositDisputePolicy) -> Decimal {
+        match (record_kind, policy) {
+            (RecordKind::Deposit, DepositDisputePolicy::CapAtAvailable) => (*val).min(self.available.max(dec!(0))),
+            _ => *val
         }
     }
     pub fn resolve_funds(&mut self, val: &Decimal, record_kind: &RecordKind) {
-        match record_kind {
-            RecordKind::Deposit => {
-                self.available += val;
-                self.held -= val
-            },
-            RecordKind::Withdrawal => {
-                self.available -= val;
-                self.held += val
-            }
-        }
+        self.apply_dispute(DisputeStep::Resolve, record_kind, val, WithdrawalDisputePolicy::Mirror)
     }
     pub fn chargeback_funds(&mut self, val: &Decimal, record_kind: &RecordKind) {
-        match record_kind {
-            RecordKind::Deposit => {
-                self.held -= val
-            },
-            RecordKind::Withdrawal => {
-                self.held += val
-
+        self.apply_dispute(DisputeStep::Chargeback, record_kind, val, WithdrawalDisputePolicy::Mirror)
+    }
+    /// Every balance transition the processor makes, deciding both whether the account accepts the
+    /// action and how it moves funds. Returns the amount moved, which a capped dispute may reduce, or
+    /// `None` when the account is left untouched: a withdrawal from a locked or short account, a
+    /// dispute on a locked account, or a resolve or chargeback on one without `allow_resolve_on_locked`.
+    /// A withdrawal that would take available below `min_balance` counts as short.
+    /// Deposits are always credited, since `locked_behavior` is applied before they get here.
+    pub fn apply(&mut self, record_kind: &RecordKind, action: AccountAction, amount: &Decimal, config: &ProcessorConfig) -> Option<Decimal> {
+        let settles = !self.locked || config.allow_resolve_on_locked;
+        match (action, record_kind) {
+            (AccountAction::Post, RecordKind::Deposit) => self.modify_available(amount, record_kind),
+            (AccountAction::Post, RecordKind::Withdrawal)
+                if !self.locked && self.available >= *amount
+                    && config.min_balance.is_none_or(|min| self.available - *amount >= min) =>
+            {
+                self.modify_available(amount, record_kind)
+            }
+            (AccountAction::Post, RecordKind::Withdrawal) => return None,
+            (AccountAction::Dispute, _) if self.locked => return None,
+            (AccountAction::Dispute, _) => {
+                let held = self.dispute_hold(amount, record_kind, config.deposit_dispute_policy);
+                self.apply_dispute(DisputeStep::Dispute, record_kind, &held, config.withdrawal_dispute_policy);
+                return Some(held);
             }
+            (AccountAction::Resolve | AccountAction::Chargeback, _) if !settles => return None,
+            (AccountAction::Resolve, _) => {
+                self.apply_dispute(DisputeStep::Resolve, record_kind, amount, config.withdrawal_dispute_policy)
+            }
+            (AccountAction::Chargeback, _) => {
+                self.apply_dispute(DisputeStep::Chargeback, record_kind, amount, config.withdrawal_dispute_policy);
+                if let Some(fee) = &config.chargeback_fee {
+                    self.modify_available(fee, &RecordKind::Withdrawal);
+                }
+            }
+        }
+        Some(*amount)
+    }
+    /// Every balance movement of the dispute lifecycle, as (available, held) deltas:
+    ///
+    /// | step       | deposit  | withdrawal, Mirror | withdrawal, HoldCredit |
+    /// |------------|----------|--------------------|------------------------|
+    /// | dispute    | (-a, +a) | (+a, -a)           | (0, +a)                |
+    /// | resolve    | (+a, -a) | (-a, +a)           | (0, -a)                |
+    /// | chargeback | (0, -a)  | (0, +a)            | (+a, -a)               |
+    ///
+    /// A chargeback also locks the account.
+    pub fn apply_dispute(&mut self, step: DisputeStep, record_kind: &RecordKind, val: &Decimal, policy: WithdrawalDisputePolicy) {
+        let a = *val;
+        let zero = dec!(0);
+        let (available, held) = match (record_kind, policy, step) {
+            (RecordKind::Deposit, _, DisputeStep::Dispute) => (-a, a),
+            (RecordKind::Deposit, _, DisputeStep::Resolve) => (a, -a),
+            (RecordKind::Deposit, _, DisputeStep::Chargeback) => (zero, -a),
+            (RecordKind::Withdrawal, WithdrawalDisputePolicy::Mirror, DisputeStep::Dispute) => (a, -a),
+            (RecordKind::Withdrawal, WithdrawalDisputePolicy::Mirror, DisputeStep::Resolve) => (-a, a),
+            (RecordKind::Withdrawal, WithdrawalDisputePolicy::Mirror, DisputeStep::Chargeback) => (zero, a),
+            (RecordKind::Withdrawal, WithdrawalDisputePolicy::HoldCredit, DisputeStep::Dispute) => (zero, a),
+            (RecordKind::Withdrawal, WithdrawalDisputePolicy::HoldCredit, DisputeStep::Resolve) => (zero, -a),
+            (RecordKind::Withdrawal, WithdrawalDisputePolicy::HoldCredit, DisputeStep::Chargeback) => (a, -a),
+        };
+        self.available += available;
+        self.held += held;
+        if step == DisputeStep::Chargeback {
+            self.lock();
         }
-        self.lock();
+    }
+    /// (available, held, total, locked) read in one call for the output path.
+    pub fn report_fields(&self) -> (Decimal, Decimal, Decimal, bool) {
+        (self.available, self.held, self.available + self.held, self.locked)
     }
     pub fn available(&self) -> Decimal { self.available }
     pub fn held(&self) -> Decimal { self.held }
@@ -82,13 +254,91 @@ impl Account {
     pub fn unlock(&mut self) { self.locked = false}
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AccountReport {
+    pub client: ClientId,
+    pub currency: Option<CurrencyCode>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl AccountReport {
+    pub fn new(key: &AccountKey, account: &Account) -> AccountReport {
+        let (available, held, total, locked) = account.report_fields();
+        AccountReport {
+            client: key.0,
+            currency: key.1.clone(),
+            available,
+            held,
+            total,
+            locked,
+        }
+    }
+}
+
+/// An account's balances right after one event was applied to it, as recorded under `trace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRow {
+    pub tx: TxId,
+    pub kind: TxKindRaw,
+    pub client: ClientId,
+    pub currency: Option<CurrencyCode>,
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+/// How one event changed its account, as recorded under `record_deltas`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccountDelta {
+    pub tx: TxId,
+    pub kind: TxKindRaw,
+    pub client: ClientId,
+    pub currency: Option<CurrencyCode>,
+    pub available_delta: Decimal,
+    pub held_delta: Decimal,
+    pub was_locked: bool,
+    pub locked: bool,
+}
+
+/// Totals across every account, as printed by `--summary`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub clients: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub expected: AccountReport,
+    pub actual: AccountReport,
+}
+
+/// An account whose report row differs between two processors, `None` on the side without it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDiff {
+    pub key: AccountKey,
+    pub ours: Option<AccountReport>,
+    pub theirs: Option<AccountReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxRecord {
     client:  ClientId,
     amount:  Decimal,
     disputed: bool,
     charged_back: bool,
     kind:    RecordKind, // Deposit | Withdrawal
+    currency: Option<CurrencyCode>,
+    rejected: bool,
+    disputed_amount: Decimal,
+    timestamp: Option<Timestamp>,
+    line: Option<u64>,
+    to_client: Option<ClientId>,
+    frozen: bool,
 }
 
 impl TxRecord {
@@ -99,28 +349,212 @@ impl TxRecord {
             disputed,
             charged_back: false,
             kind,
+            currency: None,
+            rejected: false,
+            disputed_amount: if disputed { amount } else { dec!(0) },
+            timestamp: None,
+            line: None,
+            to_client: None,
+            frozen: false,
         }
     }
+    pub fn with_currency(mut self, currency: Option<CurrencyCode>) -> TxRecord {
+        self.currency = currency;
+        self
+    }
+    pub fn with_timestamp(mut self, timestamp: Option<Timestamp>) -> TxRecord {
+        self.timestamp = timestamp;
+        self
+    }
+    pub fn with_line(mut self, line: Option<u64>) -> TxRecord {
+        self.line = line;
+        self
+    }
+    pub fn with_to_client(mut self, to_client: Option<ClientId>) -> TxRecord {
+        self.to_client = to_client;
+        self
+    }
     pub fn client(&self) -> &ClientId { &self.client }
     pub fn amount(&self) -> &Decimal { &self.amount }
     pub fn disputed(&self) -> &bool { &self.disputed }
     pub fn charged_back(&self) -> &bool { &self.charged_back }
     pub fn kind(&self) -> &RecordKind { &self.kind }
-    pub fn modify_disputed(&mut self, val: bool) { self.disputed = val }
+    pub fn currency(&self) -> &Option<CurrencyCode> { &self.currency }
+    pub fn timestamp(&self) -> &Option<Timestamp> { &self.timestamp }
+    /// Input line of the deposit or withdrawal row this record came from.
+    pub fn line(&self) -> &Option<u64> { &self.line }
+    /// The client credited by a transfer, which is recorded as a withdrawal from `client`.
+    pub fn to_client(&self) -> &Option<ClientId> { &self.to_client }
+    /// A withdrawal that was recorded but never applied to the account.
+    pub fn rejected(&self) -> &bool { &self.rejected }
+    pub fn reject(&mut self) { self.rejected = true }
+    /// `freeze_rule` locked the account at an event on this tx.
+    pub fn frozen(&self) -> &bool { &self.frozen }
+    pub fn freeze(&mut self) { self.frozen = true }
+    pub fn account_key(&self) -> AccountKey { (self.client, self.currency.clone()) }
+    /// The part of the amount currently held by a dispute, or charged back once finished.
+    pub fn disputed_amount(&self) -> &Decimal { &self.disputed_amount }
+    pub fn disputable(&self) -> Decimal { self.amount - self.disputed_amount }
+    pub fn modify_disputed(&mut self, val: bool) {
+        self.disputed = val;
+        self.disputed_amount = if val { self.amount } else { dec!(0) };
+    }
+    pub fn hold_partial(&mut self, val: &Decimal) {
+        self.disputed = true;
+        self.disputed_amount += val;
+    }
     pub fn finish_chargeback(&mut self) { self.charged_back = true  }
+    /// Every field at once, for exporting the history.
+    pub fn view(&self, tx: TxId) -> TxRecordView {
+        TxRecordView {
+            tx,
+            client: self.client,
+            currency: self.currency.clone(),
+            kind: self.kind.clone(),
+            amount: self.amount,
+            disputed: self.disputed,
+            disputed_amount: self.disputed_amount,
+            charged_back: self.charged_back,
+            rejected: self.rejected,
+            timestamp: self.timestamp,
+            line: self.line,
+            to_client: self.to_client,
+            frozen: self.frozen,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all(deserialize="lowercase"))]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TxRecordView {
+    pub tx: TxId,
+    pub client: ClientId,
+    pub currency: Option<CurrencyCode>,
+    pub kind: RecordKind,
+    pub amount: Decimal,
+    pub disputed: bool,
+    pub disputed_amount: Decimal,
+    pub charged_back: bool,
+    pub rejected: bool,
+    pub timestamp: Option<Timestamp>,
+    pub line: Option<u64>,
+    pub to_client: Option<ClientId>,
+    pub frozen: bool,
+}
+
+/// A point in time as seconds and nanoseconds since the Unix epoch, parsed from RFC 3339, e.g.
+/// `2024-05-01T12:00:00Z` or `2024-05-01T14:00:00.25+02:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+
+impl Timestamp {
+    pub fn from_unix(secs: i64, nanos: u32) -> Timestamp { Timestamp { secs, nanos } }
+    pub fn secs(&self) -> i64 { self.secs }
+    pub fn nanos(&self) -> u32 { self.nanos }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Timestamp, String> {
+        let invalid = || format!("Invalid RFC 3339 timestamp {:?}", raw);
+        let b = raw.as_bytes();
+        let number = |from: usize, to: usize| -> Result<i64, String> {
+            match b.get(from..to) {
+                Some(digits) if digits.iter().all(u8::is_ascii_digit) => {
+                    Ok(digits.iter().fold(0, |n, d| n * 10 + (d - b'0') as i64))
+                }
+                _ => Err(invalid())
+            }
+        };
+        if b.len() < 20 || b[4] != b'-' || b[7] != b'-' || !matches!(b[10], b'T' | b't' | b' ') || b[13] != b':' || b[16] != b':' {
+            return Err(invalid());
+        }
+        let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+        let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 60 {
+            return Err(invalid());
+        }
+        let mut at = 19;
+        let mut nanos = 0u32;
+        if b[at] == b'.' {
+            let digits = b[at + 1..].iter().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 {
+                return Err(invalid());
+            }
+            // digits past nanosecond precision are dropped
+            for (i, d) in b[at + 1..at + 1 + digits.min(9)].iter().enumerate() {
+                nanos += (d - b'0') as u32 * 10u32.pow(8 - i as u32);
+            }
+            at += 1 + digits;
+        }
+        let offset = match b.get(at) {
+            Some(b'Z' | b'z') if b.len() == at + 1 => 0,
+            Some(sign @ (b'+' | b'-')) if b.len() == at + 6 && b[at + 3] == b':' => {
+                let (hours, minutes) = (number(at + 1, at + 3)?, number(at + 4, at + 6)?);
+                if hours > 23 || minutes > 59 {
+                    return Err(invalid());
+                }
+                let offset = hours * 3600 + minutes * 60;
+                if *sign == b'-' { -offset } else { offset }
+            }
+            _ => return Err(invalid())
+        };
+        let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+        Ok(Timestamp { secs, nanos })
+    }
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all="lowercase")]
 pub enum TxKindRaw {
     Deposit,
     Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Transfer
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DisputeStep {
     Dispute,
     Resolve,
     Chargeback
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+/// What is done to an account: posting a deposit or withdrawal, or a step of a dispute against one.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum AccountAction {
+    Post,
+    Dispute,
+    Resolve,
+    Chargeback
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
 pub enum RecordKind {
     Deposit,
     Withdrawal
@@ -128,10 +562,15 @@ pub enum RecordKind {
 
 pub type ClientId = u16;
 pub type TxId = u32;
+pub type CurrencyCode = String;
+/// Accounts are held per client and currency; `None` is the implicit currency of files without a
+/// currency column.
+pub type AccountKey = (ClientId, Option<CurrencyCode>);
 
 #[cfg(test)]
 mod test_account {
     use rust_decimal::dec;
+    use crate::config::DepositDisputePolicy;
     use crate::model::{Account, RecordKind};
 
     #[test]
@@ -142,6 +581,21 @@ mod test_account {
         assert!(!account.locked);
     }
 
+    #[test]
+    fn test_from_parts_with_getters() {
+        let account = Account::from_parts(dec!(4.25), dec!(-1.5), true);
+        assert_eq!(account.available(), dec!(4.25));
+        assert_eq!(account.held(), dec!(-1.5));
+        assert_eq!(account.total(), dec!(2.75));
+        assert!(account.locked());
+    }
+
+    #[test]
+    fn test_report_fields() {
+        let account = Account::from_parts(dec!(4.25), dec!(-1.5), true);
+        assert_eq!(account.report_fields(), (dec!(4.25), dec!(-1.5), dec!(2.75), true));
+    }
+
     #[test]
     fn test_account_total() {
         let mut account = Account::new();
@@ -164,6 +618,14 @@ mod test_account {
 
     }
 
+    #[test]
+    fn test_default_matches_new() {
+        let zeroed = Account::from_parts(dec!(0), dec!(0), false);
+        assert_eq!(Account::default().report_fields(), zeroed.report_fields());
+        assert_eq!(Account::new().report_fields(), zeroed.report_fields());
+        assert_eq!(Account::default().available().scale(), 0);
+    }
+
     #[test]
     fn test_lock_unlock() {
         let mut account = Account::new();
@@ -184,12 +646,179 @@ mod test_account {
         assert_eq!(account.available, dec!(10));
         assert_eq!(account.held, dec!(0));
     }
+
+    #[test]
+    fn test_dispute_hold_fully_available() {
+        let account = Account::from_parts(dec!(10), dec!(0), false);
+        assert_eq!(account.dispute_hold(&dec!(7), &RecordKind::Deposit, DepositDisputePolicy::CapAtAvailable), dec!(7));
+        assert_eq!(account.dispute_hold(&dec!(7), &RecordKind::Deposit, DepositDisputePolicy::HoldFull), dec!(7));
+    }
+
+    #[test]
+    fn test_dispute_hold_partially_available() {
+        let account = Account::from_parts(dec!(4), dec!(0), false);
+        assert_eq!(account.dispute_hold(&dec!(7), &RecordKind::Deposit, DepositDisputePolicy::CapAtAvailable), dec!(4));
+        assert_eq!(account.dispute_hold(&dec!(7), &RecordKind::Deposit, DepositDisputePolicy::HoldFull), dec!(7));
+        assert_eq!(account.dispute_hold(&dec!(7), &RecordKind::Withdrawal, DepositDisputePolicy::CapAtAvailable), dec!(7));
+        let overdrawn = Account::from_parts(dec!(-2), dec!(0), false);
+        assert_eq!(overdrawn.dispute_hold(&dec!(7), &RecordKind::Deposit, DepositDisputePolicy::CapAtAvailable), dec!(0));
+    }
+}
+
+#[cfg(test)]
+mod test_apply_dispute {
+    use rust_decimal::{dec, Decimal};
+    use crate::config::WithdrawalDisputePolicy;
+    use crate::model::{Account, DisputeStep, RecordKind};
+
+    fn apply(step: DisputeStep, kind: RecordKind, policy: WithdrawalDisputePolicy) -> Account {
+        let mut account = Account::from_parts(dec!(10), dec!(0), false);
+        account.apply_dispute(step, &kind, &dec!(2), policy);
+        account
+    }
+
+    #[test]
+    fn test_all_combinations() {
+        use DisputeStep::*;
+        use RecordKind::*;
+        use WithdrawalDisputePolicy::*;
+        let cases: Vec<(DisputeStep, RecordKind, WithdrawalDisputePolicy, Decimal, Decimal)> = vec![
+            (Dispute, Deposit, Mirror, dec!(8), dec!(2)),
+            (Resolve, Deposit, Mirror, dec!(12), dec!(-2)),
+            (Chargeback, Deposit, Mirror, dec!(10), dec!(-2)),
+            (Dispute, Deposit, HoldCredit, dec!(8), dec!(2)),
+            (Resolve, Deposit, HoldCredit, dec!(12), dec!(-2)),
+            (Chargeback, Deposit, HoldCredit, dec!(10), dec!(-2)),
+            (Dispute, Withdrawal, Mirror, dec!(12), dec!(-2)),
+            (Resolve, Withdrawal, Mirror, dec!(8), dec!(2)),
+            (Chargeback, Withdrawal, Mirror, dec!(10), dec!(2)),
+            (Dispute, Withdrawal, HoldCredit, dec!(10), dec!(2)),
+            (Resolve, Withdrawal, HoldCredit, dec!(10), dec!(-2)),
+            (Chargeback, Withdrawal, HoldCredit, dec!(12), dec!(-2)),
+        ];
+        for (step, kind, policy, available, held) in cases {
+            let account = apply(step, kind.clone(), policy);
+            assert_eq!(account.available(), available, "{:?} {:?} {:?}", step, kind, policy);
+            assert_eq!(account.held(), held, "{:?} {:?} {:?}", step, kind, policy);
+            assert_eq!(account.locked(), step == Chargeback, "{:?} {:?} {:?}", step, kind, policy);
+        }
+    }
+
+    #[test]
+    fn test_withdrawal_lifecycles_net_out() {
+        for policy in [WithdrawalDisputePolicy::Mirror, WithdrawalDisputePolicy::HoldCredit] {
+            let mut resolved = Account::from_parts(dec!(10), dec!(0), false);
+            resolved.apply_dispute(DisputeStep::Dispute, &RecordKind::Withdrawal, &dec!(2), policy);
+            resolved.apply_dispute(DisputeStep::Resolve, &RecordKind::Withdrawal, &dec!(2), policy);
+            assert_eq!((resolved.available(), resolved.held()), (dec!(10), dec!(0)));
+
+            let mut charged_back = Account::from_parts(dec!(10), dec!(0), false);
+            charged_back.apply_dispute(DisputeStep::Dispute, &RecordKind::Withdrawal, &dec!(2), policy);
+            charged_back.apply_dispute(DisputeStep::Chargeback, &RecordKind::Withdrawal, &dec!(2), policy);
+            assert_eq!((charged_back.available(), charged_back.held()), (dec!(12), dec!(0)));
+            assert!(charged_back.locked());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_account_apply {
+    use rust_decimal::{dec, Decimal};
+    use crate::config::{DepositDisputePolicy, ProcessorConfig, WithdrawalDisputePolicy};
+    use crate::model::{Account, AccountAction, RecordKind};
+
+    type Outcome = (Option<Decimal>, Decimal, Decimal, bool);
+
+    fn apply(start: (Decimal, Decimal, bool), kind: RecordKind, action: AccountAction, config: &ProcessorConfig) -> Outcome {
+        let mut account = Account::from_parts(start.0, start.1, start.2);
+        let moved = account.apply(&kind, action, &dec!(4), config);
+        (moved, account.available(), account.held(), account.locked())
+    }
+
+    #[test]
+    fn test_unlocked_account() {
+        let config = ProcessorConfig::default();
+        let start = (dec!(10), dec!(2), false);
+        let cases: [(RecordKind, AccountAction, Outcome); 8] = [
+            (RecordKind::Deposit, AccountAction::Post, (Some(dec!(4)), dec!(14), dec!(2), false)),
+            (RecordKind::Withdrawal, AccountAction::Post, (Some(dec!(4)), dec!(6), dec!(2), false)),
+            (RecordKind::Deposit, AccountAction::Dispute, (Some(dec!(4)), dec!(6), dec!(6), false)),
+            (RecordKind::Withdrawal, AccountAction::Dispute, (Some(dec!(4)), dec!(14), dec!(-2), false)),
+            (RecordKind::Deposit, AccountAction::Resolve, (Some(dec!(4)), dec!(14), dec!(-2), false)),
+            (RecordKind::Withdrawal, AccountAction::Resolve, (Some(dec!(4)), dec!(6), dec!(6), false)),
+            (RecordKind::Deposit, AccountAction::Chargeback, (Some(dec!(4)), dec!(10), dec!(-2), true)),
+            (RecordKind::Withdrawal, AccountAction::Chargeback, (Some(dec!(4)), dec!(10), dec!(6), true)),
+        ];
+        for (kind, action, expected) in cases {
+            assert_eq!(apply(start, kind.clone(), action, &config), expected, "{:?} {:?}", action, kind);
+        }
+        let short = (dec!(3), dec!(2), false);
+        assert_eq!(apply(short, RecordKind::Withdrawal, AccountAction::Post, &config), (None, dec!(3), dec!(2), false));
+    }
+
+    #[test]
+    fn test_locked_account() {
+        let start = (dec!(10), dec!(2), true);
+        let settling = ProcessorConfig {
+            allow_resolve_on_locked: true,
+            ..Default::default()
+        };
+        for config in [ProcessorConfig::default(), settling.clone()] {
+            assert_eq!(apply(start, RecordKind::Deposit, AccountAction::Post, &config), (Some(dec!(4)), dec!(14), dec!(2), true));
+            assert_eq!(apply(start, RecordKind::Withdrawal, AccountAction::Post, &config), (None, dec!(10), dec!(2), true));
+            for kind in [RecordKind::Deposit, RecordKind::Withdrawal] {
+                assert_eq!(apply(start, kind, AccountAction::Dispute, &config), (None, dec!(10), dec!(2), true));
+            }
+        }
+        for action in [AccountAction::Resolve, AccountAction::Chargeback] {
+            for kind in [RecordKind::Deposit, RecordKind::Withdrawal] {
+                assert_eq!(apply(start, kind, action, &ProcessorConfig::default()), (None, dec!(10), dec!(2), true));
+            }
+        }
+        assert_eq!(apply(start, RecordKind::Deposit, AccountAction::Resolve, &settling), (Some(dec!(4)), dec!(14), dec!(-2), true));
+        assert_eq!(apply(start, RecordKind::Deposit, AccountAction::Chargeback, &settling), (Some(dec!(4)), dec!(10), dec!(-2), true));
+    }
+
+    #[test]
+    fn test_policies_and_fee() {
+        let capped = ProcessorConfig {
+            deposit_dispute_policy: DepositDisputePolicy::CapAtAvailable,
+            ..Default::default()
+        };
+        assert_eq!(apply((dec!(3), dec!(0), false), RecordKind::Deposit, AccountAction::Dispute, &capped), (Some(dec!(3)), dec!(0), dec!(3), false));
+        let hold_credit = ProcessorConfig {
+            withdrawal_dispute_policy: WithdrawalDisputePolicy::HoldCredit,
+            ..Default::default()
+        };
+        assert_eq!(apply((dec!(10), dec!(2), false), RecordKind::Withdrawal, AccountAction::Dispute, &hold_credit), (Some(dec!(4)), dec!(10), dec!(6), false));
+        let fee = ProcessorConfig {
+            chargeback_fee: Some(dec!(1)),
+            ..Default::default()
+        };
+        assert_eq!(apply((dec!(10), dec!(6), false), RecordKind::Deposit, AccountAction::Chargeback, &fee), (Some(dec!(4)), dec!(9), dec!(2), true));
+    }
+
+    #[test]
+    fn test_withdrawal_of_whole_balance() {
+        let config = ProcessorConfig::default();
+        assert_eq!(apply((dec!(4.0000), dec!(0), false), RecordKind::Withdrawal, AccountAction::Post, &config), (Some(dec!(4)), dec!(0), dec!(0), false));
+        let mut account = Account::from_parts(dec!(0.3), dec!(0), false);
+        assert_eq!(account.apply(&RecordKind::Withdrawal, AccountAction::Post, &(dec!(0.1) + dec!(0.2)), &config), Some(dec!(0.3)));
+        assert!(account.available().is_zero());
+
+        let floor = ProcessorConfig {
+            min_balance: Some(dec!(1)),
+            ..Default::default()
+        };
+        assert_eq!(apply((dec!(4), dec!(0), false), RecordKind::Withdrawal, AccountAction::Post, &floor), (None, dec!(4), dec!(0), false));
+        assert_eq!(apply((dec!(5), dec!(0), false), RecordKind::Withdrawal, AccountAction::Post, &floor), (Some(dec!(4)), dec!(1), dec!(0), false));
+    }
 }
 
 #[cfg(test)]
 mod test_tx_record {
     use rust_decimal::dec;
-    use crate::model::{RecordKind, TxRecord};
+    use crate::model::{RecordKind, TxRecord, TxRecordView};
 
     #[test]
     fn test_new_with_getters() {
@@ -207,6 +836,29 @@ mod test_tx_record {
         assert!(!record.disputed());
     }
 
+    #[test]
+    fn test_view_after_dispute() {
+        let mut record = TxRecord::new(4, dec!(2.5), false, RecordKind::Deposit);
+        record.modify_disputed(true);
+        assert_eq!(record.view(9), TxRecordView {
+            tx: 9,
+            client: 4,
+            currency: None,
+            kind: RecordKind::Deposit,
+            amount: dec!(2.5),
+            disputed: true,
+            disputed_amount: dec!(2.5),
+            charged_back: false,
+            rejected: false,
+            timestamp: None,
+            line: None,
+            to_client: None,
+            frozen: false,
+        });
+        assert_eq!(serde_json::to_string(&record.view(9)).unwrap(), "{\"tx\":9,\"client\":4,\"currency\":null,\"kind\":\"deposit\",\
+            \"amount\":\"2.5\",\"disputed\":true,\"disputed_amount\":\"2.5\",\"charged_back\":false,\"rejected\":false,\"timestamp\":null,\"line\":null,\"to_client\":null,\"frozen\":false}");
+    }
+
     #[test]
     fn test_modify_disputed() {
         let mut record = TxRecord::new(
@@ -217,7 +869,185 @@ mod test_tx_record {
         );
         record.modify_disputed(true);
         assert!(record.disputed());
+        assert_eq!(record.disputed_amount(), &dec!(1));
         record.finish_chargeback();
         assert!(record.charged_back());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_hold_partial() {
+        let mut record = TxRecord::new(
+            1,
+            dec!(10),
+            false,
+            RecordKind::Deposit
+        );
+        record.hold_partial(&dec!(4));
+        assert!(record.disputed());
+        assert_eq!(record.disputable(), dec!(6));
+        record.hold_partial(&dec!(6));
+        assert_eq!(record.disputed_amount(), &dec!(10));
+        assert_eq!(record.disputable(), dec!(0));
+        record.modify_disputed(false);
+        assert_eq!(record.disputed_amount(), &dec!(0));
+    }
+}
+
+#[cfg(test)]
+mod test_tx_event {
+    use std::error::Error;
+    use rust_decimal::{dec, Decimal};
+    use crate::config::{AmountParser, AmountSanitizer, ProcessorConfig, SharedAmountParser};
+    use crate::error::ProcessorError;
+    use crate::model::{TxEvent, TxId, TxKindRaw, TxRow};
+
+    fn row(amount: &str) -> TxRow {
+        TxRow {
+            kind: TxKindRaw::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(amount.to_string()),
+            currency: None,
+            to_client: None,
+            timestamp: None
+        }
+    }
+
+    #[test]
+    fn test_plain_amount() {
+        let ev = TxEvent::try_from_row(row("1.5"), &ProcessorConfig::default()).unwrap();
+        assert_eq!(ev.amount, Some(dec!(1.5)));
+    }
+
+    #[test]
+    fn test_unicode_digits() {
+        let config = ProcessorConfig {
+            unicode_digits: true,
+            ..Default::default()
+        };
+        for (raw, expected) in [("５．２５", dec!(5.25)), ("٥٫٢٥", dec!(5.25)), ("۱۲۰", dec!(120)), ("१.5", dec!(1.5))] {
+            assert_eq!(TxEvent::try_from_row(row(raw), &config).unwrap().amount, Some(expected), "{}", raw);
+        }
+        assert!(TxEvent::try_from_row(row("５．２５"), &ProcessorConfig::default()).is_err());
+    }
+
+    /// Amounts written in whole cents as `<cents>c`.
+    struct CentsParser;
+
+    impl AmountParser for CentsParser {
+        fn parse(&self, raw: &str, tx: TxId) -> Result<Decimal, Box<dyn Error>> {
+            let cents = raw.strip_suffix('c').ok_or_else(|| format!("Expected cents for tx {}", tx))?;
+            Ok(Decimal::new(cents.parse()?, 2))
+        }
+    }
+
+    #[test]
+    fn test_custom_amount_parser() {
+        let config = ProcessorConfig {
+            amount_parser: SharedAmountParser::new(CentsParser),
+            ..Default::default()
+        };
+        let ev = TxEvent::try_from_row(row("150c"), &config).unwrap();
+        assert_eq!(ev.amount, Some(dec!(1.50)));
+        let err = TxEvent::try_from_row(row("1.5"), &config).unwrap_err();
+        assert_eq!(err.to_string(), "Expected cents for tx 1");
+    }
+
+    #[test]
+    fn test_sanitized_amount() {
+        let config = ProcessorConfig {
+            sanitize_amounts: Some(AmountSanitizer::default()),
+            ..Default::default()
+        };
+        let ev = TxEvent::try_from_row(row("$1,234.56"), &config).unwrap();
+        assert_eq!(ev.amount, Some(dec!(1234.56)));
+        let ev = TxEvent::try_from_row(row("-$12,345,678"), &config).unwrap();
+        assert_eq!(ev.amount, Some(dec!(-12345678)));
+    }
+
+    #[test]
+    fn test_sanitizer_rejects_misplaced_separators() {
+        let config = ProcessorConfig {
+            sanitize_amounts: Some(AmountSanitizer::default()),
+            ..Default::default()
+        };
+        for raw in ["1,00", "12,34.5", "1.234,56", ",123", "1,234,"] {
+            let err = TxEvent::try_from_row(row(raw), &config).unwrap_err();
+            assert_eq!(err.to_string(), format!("Invalid amount {:?} for tx 1", raw));
+        }
+    }
+
+    #[test]
+    fn test_rejects_amount_beyond_decimal_precision() {
+        let result = TxEvent::try_from_row(row("1234567890123456789012345678901234567890"), &ProcessorConfig::default());
+        let err = result.err().unwrap();
+        assert!(matches!(err.downcast_ref::<ProcessorError>(), Some(ProcessorError::PrecisionLoss { tx: 1, .. })));
+        let result = TxEvent::try_from_row(row("0.1234567890123456789012345678901234567890"), &ProcessorConfig::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_accepts_amount_at_decimal_precision() {
+        let ev = TxEvent::try_from_row(row("123456789012345678.9012345678000"), &ProcessorConfig::default()).unwrap();
+        assert_eq!(ev.amount, Some(dec!(123456789012345678.9012345678)));
+    }
+
+    #[test]
+    fn test_scientific_amount() {
+        let ev = TxEvent::try_from_row(row("1.5e2"), &ProcessorConfig::default()).unwrap();
+        assert_eq!(ev.amount, Some(dec!(150)));
+        let ev = TxEvent::try_from_row(row("2E-3"), &ProcessorConfig::default()).unwrap();
+        assert_eq!(ev.amount, Some(dec!(0.002)));
+    }
+
+    #[test]
+    fn test_signed_amounts() {
+        let config = ProcessorConfig {
+            signed_amounts: true,
+            ..Default::default()
+        };
+        let ev = TxEvent::try_from_row(row("-2.5"), &config).unwrap();
+        assert_eq!((ev.kind, ev.amount), (TxKindRaw::Withdrawal, Some(dec!(2.5))));
+        let ev = TxEvent::try_from_row(TxRow { kind: TxKindRaw::Withdrawal, ..row("2.5") }, &config).unwrap();
+        assert_eq!((ev.kind, ev.amount), (TxKindRaw::Deposit, Some(dec!(2.5))));
+        let ev = TxEvent::try_from_row(row("-2.5"), &ProcessorConfig::default()).unwrap();
+        assert_eq!((ev.kind, ev.amount), (TxKindRaw::Deposit, Some(dec!(-2.5))));
+    }
+
+    #[test]
+    fn test_default_rejects_formatted_amount() {
+        let result = TxEvent::try_from_row(row("$1,234.56"), &ProcessorConfig::default());
+        assert!(result.is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_timestamp {
+    use crate::model::Timestamp;
+
+    #[test]
+    fn test_parse_utc() {
+        let at: Timestamp = "2024-05-01T12:00:00Z".parse().unwrap();
+        assert_eq!(at, Timestamp::from_unix(1714564800, 0));
+        let epoch: Timestamp = "1970-01-01T00:00:00Z".parse().unwrap();
+        assert_eq!(epoch, Timestamp::from_unix(0, 0));
+        let before: Timestamp = "1969-12-31T23:59:59Z".parse().unwrap();
+        assert_eq!(before, Timestamp::from_unix(-1, 0));
+    }
+
+    #[test]
+    fn test_parse_offset_and_fraction() {
+        let at: Timestamp = "2024-05-01T14:00:00.25+02:00".parse().unwrap();
+        assert_eq!(at, Timestamp::from_unix(1714564800, 250_000_000));
+        let at: Timestamp = "2024-02-29T00:00:00.1234567891-00:30".parse().unwrap();
+        assert_eq!(at, Timestamp::from_unix(1709166600, 123_456_789));
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        for raw in ["", "2024-05-01", "2024-05-01T12:00:00", "2023-02-29T00:00:00Z", "2024-13-01T00:00:00Z",
+            "2024-05-01T12:00:00.Z", "2024-05-01T12:00:00+0200", "2024-05-01T12:00:00Zjunk"] {
+            assert!(raw.parse::<Timestamp>().is_err(), "{:?} should not parse", raw);
+        }
+    }
+}
@@ -1,162 +1,1155 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::ffi::OsString;
 use std::fs::File;
-use dashmap::{DashMap, Entry};
-use rust_decimal::dec;
-use crate::model::{Account, ClientId, RecordKind, TxEvent, TxId, TxKindRaw, TxRecord};
-use crate::processor;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use csv::StringRecord;
+use dashmap::{DashMap, DashSet, Entry};
+use log::{error, warn};
+use rust_decimal::{dec, Decimal};
+use serde::{Deserialize, Serialize};
+use crate::config::{EmptyAmount, ErrorSink, LockedBehavior, ProcessorConfig, WithdrawalDisputePolicy};
+use crate::error::{ErrorReportFormat, HeldRatioAlert, InvariantViolation, ProcessorError, RowError};
+use crate::history::TxHistory;
+use crate::model::{Account, AccountAction, AccountDelta, AccountDiff, AccountKey, AccountReport, ClientId, Discrepancy, DisputeStep, RecordKind, Summary, Timestamp, TraceRow, TxEvent, TxId, TxKindRaw, TxRecord, TxRow};
 
 pub struct Processor {
-    accounts: DashMap<ClientId, Account>,
-    tx_history: DashMap<TxId, TxRecord>
+    accounts: DashMap<AccountKey, Account>,
+    tx_history: TxHistory,
+    seen: DashSet<(TxKindRaw, TxId)>,
+    duplicates: AtomicUsize,
+    failed_rows: AtomicUsize,
+    timed_out: AtomicBool,
+    recent_txs: DashMap<ClientId, RecentTxs>,
+    last_txs: DashMap<ClientId, TxId>,
+    dispute_counts: DashMap<ClientId, usize>,
+    errors: Mutex<Vec<RowError>>,
+    invariant_violations: Mutex<Vec<InvariantViolation>>,
+    held_ratio_alerts: Mutex<Vec<HeldRatioAlert>>,
+    trace: Mutex<Vec<TraceRow>>,
+    deltas: Mutex<Vec<AccountDelta>>,
+    deferred: DashMap<AccountKey, Vec<TxEvent>>,
+    dispute_shortfalls: DashMap<TxId, Decimal>,
+    config: ProcessorConfig
+}
+
+impl Default for Processor {
+    fn default() -> Processor {
+        Processor::with_config(ProcessorConfig::default())
+    }
+}
+
+/// Applies the events in order on the current thread with the default config. Events that fail are
+/// kept in `errors`, with `line` holding the event's 1-based position.
+impl FromIterator<TxEvent> for Processor {
+    fn from_iter<I: IntoIterator<Item = TxEvent>>(events: I) -> Processor {
+        let processor = Processor::default();
+        for (index, ev) in events.into_iter().enumerate() {
+            let (tx, kind) = (ev.tx, ev.kind);
+            if let Err(err) = processor.process(ev) {
+                let _ = processor.report_row_error(RowError::new(index as u64 + 1, Some(tx), Some(kind), err));
+            }
+        }
+        processor.finish();
+        processor
+    }
 }
 
 impl Processor {
-    pub fn default() -> Processor {
+    pub fn with_config(config: ProcessorConfig) -> Processor {
+        Processor::with_history(config, TxHistory::in_memory())
+    }
+    pub fn with_history(config: ProcessorConfig, tx_history: TxHistory) -> Processor {
         Processor {
-            accounts: DashMap::new(),
-            tx_history: DashMap::new()
+            accounts: DashMap::with_capacity(config.expected_clients.unwrap_or(0)),
+            tx_history,
+            seen: DashSet::new(),
+            duplicates: AtomicUsize::new(0),
+            failed_rows: AtomicUsize::new(0),
+            timed_out: AtomicBool::new(false),
+            recent_txs: DashMap::new(),
+            last_txs: DashMap::new(),
+            dispute_counts: DashMap::new(),
+            errors: Mutex::new(Vec::new()),
+            invariant_violations: Mutex::new(Vec::new()),
+            held_ratio_alerts: Mutex::new(Vec::new()),
+            trace: Mutex::new(Vec::new()),
+            deltas: Mutex::new(Vec::new()),
+            deferred: DashMap::new(),
+            dispute_shortfalls: DashMap::new(),
+            config
         }
     }
     pub fn process_file(file_path: OsString) -> Result<Processor, Box<dyn Error>> {
+        Processor::process_file_with_config(file_path, ProcessorConfig::default())
+    }
+    pub fn process_file_with_config(file_path: OsString, config: ProcessorConfig) -> Result<Processor, Box<dyn Error>> {
         let file = File::open(file_path)?;
-        let mut rdr = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .from_reader(file);
-        let processor = processor::Processor::default();
-        for record in rdr.records() {
-            let ev: TxEvent = record?.deserialize(None)?;
-            processor.process(ev)?;
+        Processor::process_reader(file, config)
+    }
+    /// Processes the files one after another into one set of accounts, each as if resumed with
+    /// `resume_reader`, so row limits and error lines apply per file. `on_account_final` runs once,
+    /// after the last file.
+    pub fn process_files(paths: &[PathBuf], config: ProcessorConfig) -> Result<Processor, Box<dyn Error>> {
+        let tx_history = TxHistory::from_config(&config)?;
+        let mut processor = Processor::with_history(config, tx_history);
+        for path in paths {
+            processor = processor.resume_reader(File::open(path)?)?;
+        }
+        processor.finish();
+        Ok(processor)
+    }
+    pub fn process_str(data: &str, config: ProcessorConfig) -> Result<Processor, Box<dyn Error>> {
+        Processor::process_reader(data.as_bytes(), config)
+    }
+    pub fn process_reader<R: Read>(reader: R, config: ProcessorConfig) -> Result<Processor, Box<dyn Error>> {
+        let tx_history = TxHistory::from_config(&config)?;
+        let processor = Processor::with_history(config, tx_history).resume_reader(reader)?;
+        processor.finish();
+        Ok(processor)
+    }
+    /// Applies another CSV input, header included, on top of the current state, e.g. after `restore`.
+    /// More input may follow, so call `finish` once the last of it has been applied.
+    pub fn resume_reader<R: Read>(self, reader: R) -> Result<Processor, Box<dyn Error>> {
+        let mut reader = RetryReader { inner: reader, attempts: self.config.read_retries, backoff: self.config.read_retry_backoff };
+        if let Some(chunks) = self.config.parse_chunks {
+            let mut input = Vec::new();
+            reader.read_to_end(&mut input)?;
+            let mut rdr = csv_reader(input.as_slice(), self.config.flexible);
+            let headers = read_headers(&mut rdr, &self.config)?;
+            let records = parse_chunked(&input, rdr.position(), chunks, !self.config.blank_lines_as_errors, self.config.flexible);
+            return self.process_records(records.into_iter(), headers.as_ref());
+        }
+        let mut rdr = csv_reader(reader, self.config.flexible);
+        let headers = read_headers(&mut rdr, &self.config)?;
+        let mut read_error = None;
+        let records = read_records(&mut rdr, !self.config.blank_lines_as_errors).map_while(|record| match record {
+            Ok(record) => Some(Ok(record)),
+            // a read still failing after `read_retries` is not a bad row: the rest of the input is lost
+            Err((err, _)) if err.is_io_error() => {
+                read_error = Some(err);
+                None
+            }
+            Err((err, bytes)) => {
+                let line = err.position().map_or(0, |pos| pos.line());
+                Some(Err(csv_row_error(err, line).with_bytes(bytes)))
+            }
+        });
+        let processor = self.process_records(records, headers.as_ref())?;
+        match read_error {
+            Some(err) => Err(Box::new(ProcessorError::from(err))),
+            None => Ok(processor)
+        }
+    }
+    fn process_records<I>(self, records: I, headers: Option<&StringRecord>) -> Result<Processor, Box<dyn Error>>
+    where
+        I: Iterator<Item = Result<StringRecord, RowError>>
+    {
+        let max_rows = self.config.max_rows.unwrap_or(usize::MAX);
+        let mut throttle = self.config.rate_limit.map(TokenBucket::new);
+        let deadline = self.config.timeout.map(|timeout| Instant::now() + timeout);
+        for record in records.take(max_rows) {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.timed_out.store(true, Ordering::Relaxed);
+                break;
+            }
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.acquire();
+            }
+            if let Err(err) = record.and_then(|record| self.process_record(record, headers)) {
+                if !self.config.continue_on_error {
+                    return Err(Box::new(err));
+                }
+                self.report_row_error(err)?;
+            }
+        }
+        if self.config.atomic && self.timed_out() {
+            return Err(From::from("Processing timed out before the end of the input"));
+        }
+        let failed = self.failed_rows.load(Ordering::Relaxed);
+        if self.config.atomic && failed > 0 {
+            return match self.errors().into_iter().next() {
+                Some(err) => Err(Box::new(err)),
+                None => Err(From::from(format!("{} rows failed", failed)))
+            };
+        }
+        Ok(self)
+    }
+    /// Writes everything later events depend on (accounts, tx history, duplicate tracking, dispute
+    /// windows, deferred events and dispute shortfalls) as JSON. Row errors are not included.
+    pub fn checkpoint<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let checkpoint = Checkpoint {
+            accounts: self.accounts.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect(),
+            records: self.tx_history.records()?,
+            seen: self.seen.iter().map(|entry| *entry).collect(),
+            duplicates: self.duplicate_count(),
+            recent_txs: self.recent_txs.iter().map(|entry| (*entry.key(), entry.value().order.clone())).collect(),
+            last_txs: self.last_txs.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+            dispute_counts: self.dispute_counts.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+            deferred: self.deferred.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect(),
+            dispute_shortfalls: self.dispute_shortfalls.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+        };
+        serde_json::to_writer(writer, &checkpoint)?;
+        Ok(())
+    }
+    /// Rebuilds a processor from a `checkpoint`, ready for `resume_reader` or `process`.
+    pub fn restore<R: Read>(reader: R, config: ProcessorConfig) -> Result<Processor, Box<dyn Error>> {
+        let checkpoint: Checkpoint = serde_json::from_reader(reader)?;
+        let tx_history = TxHistory::from_config(&config)?;
+        for (tx, record) in checkpoint.records {
+            tx_history.insert(tx, record)?;
         }
+        let mut processor = Processor::with_history(config, tx_history);
+        processor.accounts.extend(checkpoint.accounts);
+        processor.seen.extend(checkpoint.seen);
+        processor.duplicates.store(checkpoint.duplicates, Ordering::Relaxed);
+        processor.recent_txs.extend(checkpoint.recent_txs.into_iter().map(|(client, order)| (client, RecentTxs::from(order))));
+        processor.last_txs.extend(checkpoint.last_txs);
+        processor.dispute_counts.extend(checkpoint.dispute_counts);
+        processor.deferred.extend(checkpoint.deferred);
+        processor.dispute_shortfalls.extend(checkpoint.dispute_shortfalls);
         Ok(processor)
     }
-    pub fn accounts(&self) -> &DashMap<ClientId, Account> { &self.accounts }
-    pub fn tx_history(&self) -> &DashMap<TxId, TxRecord> { &self.tx_history }
+    fn process_record(&self, record: StringRecord, headers: Option<&StringRecord>) -> Result<(), RowError> {
+        let line = record.position().map_or(0, |pos| pos.line());
+        if self.config.require_all_fields {
+            check_all_fields(&record, headers).map_err(|err| RowError::new(line, None, None, err))?;
+        }
+        let row: TxRow = record.deserialize(headers).map_err(|err| RowError::new(line, None, None, err))?;
+        let (tx, kind) = (row.tx, row.kind);
+        let mut ev = TxEvent::try_from_row(row, &self.config)
+            .map_err(|err| RowError::new(line, Some(tx), Some(kind), err))?;
+        ev.line = Some(line);
+        self.process(ev).map_err(|err| RowError::new(line, Some(tx), Some(kind), err))
+    }
+    /// Routes a row error skipped under `continue_on_error` according to `error_sink`.
+    fn report_row_error(&self, err: RowError) -> Result<(), Box<dyn Error>> {
+        self.failed_rows.fetch_add(1, Ordering::Relaxed);
+        self.route_row_error(err)
+    }
+    /// Reports an event that is skipped without failing its row, so processing goes on whatever
+    /// `continue_on_error` says and `atomic` does not count it.
+    fn report_skipped(&self, ev: &TxEvent, err: ProcessorError) -> Result<(), Box<dyn Error>> {
+        self.route_row_error(RowError::new(ev.line.unwrap_or(0), Some(ev.tx), Some(ev.kind), err))
+    }
+    fn route_row_error(&self, err: RowError) -> Result<(), Box<dyn Error>> {
+        if matches!(self.config.error_sink, ErrorSink::Stderr | ErrorSink::Both) {
+            error!("Row error on line {}: {}", err.line, err);
+        }
+        if matches!(self.config.error_sink, ErrorSink::Collect | ErrorSink::Both) {
+            self.errors.lock().map_err(|_| "error list lock poisoned")?.push(err);
+        }
+        Ok(())
+    }
+    /// Ends processing: calls `on_account_final` for every account and computes the held ratio
+    /// alerts. The `process_*` constructors call it themselves; after `resume_reader` it is the
+    /// caller's to call once.
+    pub fn finish(&self) {
+        if let Some(callback) = &self.config.on_account_final {
+            for account in self.accounts.iter() {
+                callback.call(account.key().clone(), account.value().clone());
+            }
+        }
+        if let Some(ratio) = self.config.max_held_ratio {
+            let alerts: Vec<HeldRatioAlert> = self.sorted_accounts()
+                .into_iter()
+                .filter(|(_, account)| account.held() > dec!(0) && account.held() > ratio * account.available())
+                .map(|((client, currency), account)| {
+                    warn!("Client {} holds {} against {} available", client, account.held(), account.available());
+                    HeldRatioAlert { client, currency, available: account.available(), held: account.held() }
+                })
+                .collect();
+            if let Ok(mut held_ratio_alerts) = self.held_ratio_alerts.lock() {
+                *held_ratio_alerts = alerts;
+            }
+        }
+    }
+    pub fn config(&self) -> &ProcessorConfig { &self.config }
+    /// True if `timeout` stopped reading before the end of the input.
+    pub fn timed_out(&self) -> bool { self.timed_out.load(Ordering::Relaxed) }
+    pub fn errors(&self) -> Vec<RowError> {
+        self.errors.lock().map(|errors| errors.clone()).unwrap_or_default()
+    }
+    pub fn invariant_violations(&self) -> Vec<InvariantViolation> {
+        self.invariant_violations.lock().map(|violations| violations.clone()).unwrap_or_default()
+    }
+    /// Every event applied under `trace`, in order, with its account's balances afterwards.
+    pub fn trace(&self) -> Vec<TraceRow> {
+        self.trace.lock().map(|trace| trace.clone()).unwrap_or_default()
+    }
+    /// How each event applied under `record_deltas` changed its account, in order.
+    pub fn deltas(&self) -> Vec<AccountDelta> {
+        self.deltas.lock().map(|deltas| deltas.clone()).unwrap_or_default()
+    }
+    pub fn held_ratio_alerts(&self) -> Vec<HeldRatioAlert> {
+        self.held_ratio_alerts.lock().map(|alerts| alerts.clone()).unwrap_or_default()
+    }
+    pub fn write_errors<W: Write>(&self, writer: W, format: ErrorReportFormat) -> Result<(), Box<dyn Error>> {
+        let errors = self.errors();
+        match format {
+            ErrorReportFormat::Csv => {
+                let mut wtr = csv::Writer::from_writer(writer);
+                for err in errors {
+                    wtr.serialize(err)?;
+                }
+                wtr.flush()?;
+            }
+            ErrorReportFormat::Json => serde_json::to_writer(writer, &errors)?
+        }
+        Ok(())
+    }
+    pub fn accounts(&self) -> &DashMap<AccountKey, Account> { &self.accounts }
+    /// The client's account in the implicit currency.
+    pub fn account(&self, client: ClientId) -> Option<Account> {
+        self.accounts.get(&(client, None)).map(|account| account.clone())
+    }
+    pub fn currency_account(&self, client: ClientId, currency: &str) -> Option<Account> {
+        self.accounts.get(&(client, Some(currency.to_string()))).map(|account| account.clone())
+    }
+    pub fn tx_history(&self) -> &TxHistory { &self.tx_history }
+    /// Replays each account's recorded transactions from a zero balance and reports every account
+    /// whose live balances or lock disagree with the replay.
+    pub fn verify_against_history(&self) -> Result<Vec<Discrepancy>, Box<dyn Error>> {
+        let mut expected = self.replay_history(|_| true)?;
+        let mut discrepancies = Vec::new();
+        for (key, account) in self.sorted_accounts() {
+            let actual = AccountReport::new(&key, &account);
+            let replayed = expected.remove(&key).unwrap_or_default();
+            let replayed = AccountReport::new(&key, &replayed);
+            if actual != replayed {
+                discrepancies.push(Discrepancy { expected: replayed, actual });
+            }
+        }
+        Ok(discrepancies)
+    }
+    /// Balances rebuilt from the deposits and withdrawals timestamped within `from..=to`, ordered by client
+    /// and currency. Rows without a timestamp are left out once either bound is set. Disputes are applied
+    /// as they stand now rather than as of `to`, since dispute rows are not kept in the history.
+    pub fn balances_within(&self, from: Option<Timestamp>, to: Option<Timestamp>) -> Result<Vec<AccountReport>, Box<dyn Error>> {
+        let bounded = from.is_some() || to.is_some();
+        let accounts = self.replay_history(|record| match record.timestamp() {
+            None => !bounded,
+            Some(at) => from.is_none_or(|from| *at >= from) && to.is_none_or(|to| *at <= to)
+        })?;
+        let mut rows: Vec<AccountReport> = accounts.iter().map(|(key, account)| AccountReport::new(key, account)).collect();
+        rows.sort();
+        Ok(rows)
+    }
+    fn replay_history(&self, keep: impl Fn(&TxRecord) -> bool) -> Result<HashMap<AccountKey, Account>, Box<dyn Error>> {
+        let mut accounts: HashMap<AccountKey, Account> = HashMap::new();
+        for (_, record) in self.tx_history.records()? {
+            if !keep(&record) {
+                continue;
+            }
+            if *record.frozen() {
+                accounts.entry(record.account_key()).or_default().lock();
+            }
+            if *record.rejected() {
+                continue;
+            }
+            let account = accounts.entry(record.account_key()).or_default();
+            account.modify_available(record.amount(), record.kind());
+            let policy = self.config.withdrawal_dispute_policy;
+            if *record.charged_back() {
+                account.apply_dispute(DisputeStep::Dispute, record.kind(), record.disputed_amount(), policy);
+                account.apply_dispute(DisputeStep::Chargeback, record.kind(), record.disputed_amount(), policy);
+                if let Some(fee) = &self.config.chargeback_fee {
+                    account.modify_available(fee, &RecordKind::Withdrawal);
+                }
+            } else if *record.disputed() {
+                account.apply_dispute(DisputeStep::Dispute, record.kind(), record.disputed_amount(), policy);
+            }
+            if let Some(to_client) = record.to_client() {
+                accounts.entry((*to_client, record.currency().clone())).or_default().modify_available(record.amount(), &RecordKind::Deposit);
+            }
+        }
+        Ok(accounts)
+    }
+    /// Copies of every account ordered by client and currency. Aggregates walk this rather than the
+    /// map, whose iteration order varies between runs and would change the scale of summed decimals.
+    pub fn sorted_accounts(&self) -> Vec<(AccountKey, Account)> {
+        let mut accounts: Vec<(AccountKey, Account)> = self.accounts
+            .iter()
+            .map(|account| (account.key().clone(), account.value().clone()))
+            .collect();
+        accounts.sort_by(|a, b| a.0.cmp(&b.0));
+        accounts
+    }
+    /// Every account's report row, ordered by client and currency.
+    pub fn snapshot(&self) -> Vec<AccountReport> {
+        self.sorted_accounts().iter().map(|(key, account)| AccountReport::new(key, account)).collect()
+    }
+    /// Accounts whose available, held or locked differ from `other`'s, or that only one of the two
+    /// has, ordered by client and currency.
+    pub fn diff(&self, other: &Processor) -> Vec<AccountDiff> {
+        let mut rows: BTreeMap<AccountKey, AccountDiff> = BTreeMap::new();
+        for (theirs, reports) in [(false, self.snapshot()), (true, other.snapshot())] {
+            for report in reports {
+                let key = (report.client, report.currency.clone());
+                let diff = rows.entry(key.clone()).or_insert(AccountDiff { key, ours: None, theirs: None });
+                match theirs {
+                    false => diff.ours = Some(report),
+                    true => diff.theirs = Some(report)
+                }
+            }
+        }
+        rows.into_values().filter(|diff| diff.ours != diff.theirs).collect()
+    }
+    /// Consumes the processor for its report rows, ordered like `snapshot`, moving each currency code
+    /// out of the account map instead of cloning it.
+    pub fn into_report(self) -> Vec<AccountReport> {
+        let mut rows: Vec<AccountReport> = self.accounts
+            .into_iter()
+            .map(|((client, currency), account)| {
+                let (available, held, total, locked) = account.report_fields();
+                AccountReport { client, currency, available, held, total, locked }
+            })
+            .collect();
+        rows.sort();
+        rows
+    }
+    /// A snapshot that can be taken from another thread while events are still being applied through
+    /// `process`. Each account is read whole under its shard lock, but accounts are read one after
+    /// another, so the rows may mix states from before and after events applied meanwhile.
+    pub fn live_snapshot(&self) -> Vec<AccountReport> { self.snapshot() }
+    /// Client count, summed balances and locked account count in one pass over the accounts.
+    pub fn totals_summary(&self) -> Summary {
+        let mut clients = HashSet::new();
+        let mut summary = Summary::default();
+        for (key, account) in self.sorted_accounts() {
+            clients.insert(key.0);
+            summary.available += account.available();
+            summary.held += account.held();
+            if account.locked() {
+                summary.locked += 1;
+            }
+        }
+        summary.clients = clients.len();
+        summary
+    }
+    /// Held funds summed across every account; zero when there are none.
+    pub fn held_total(&self) -> Decimal {
+        self.sorted_accounts().iter().fold(dec!(0), |total, (_, account)| total + account.held())
+    }
+    /// Available funds summed across every account; zero when there are none.
+    pub fn available_total(&self) -> Decimal {
+        self.sorted_accounts().iter().fold(dec!(0), |total, (_, account)| total + account.available())
+    }
+    /// Account gauges in the Prometheus text exposition format, for a wrapping service to serve.
+    pub fn prometheus_metrics(&self) -> String {
+        let summary = self.totals_summary();
+        let gauges = [
+            ("txn_accounts", "Accounts, one per client and currency.", self.account_count().to_string()),
+            ("txn_locked_accounts", "Accounts locked by a chargeback or freeze.", summary.locked.to_string()),
+            ("txn_available_sum", "Available funds summed across every account.", summary.available.normalize().to_string()),
+            ("txn_held_sum", "Held funds summed across every account.", summary.held.normalize().to_string()),
+        ];
+        gauges.iter()
+            .map(|(name, help, value)| format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value))
+            .collect()
+    }
+    /// Deposits and withdrawals held back from locked accounts under `LockedBehavior::Defer`.
+    pub fn deferred(&self) -> &DashMap<AccountKey, Vec<TxEvent>> { &self.deferred }
+    /// The part of a disputed deposit left unheld under `DepositDisputePolicy::CapAtAvailable`.
+    pub fn dispute_shortfall(&self, tx: TxId) -> Option<Decimal> {
+        self.dispute_shortfalls.get(&tx).map(|shortfall| *shortfall)
+    }
+    pub fn account_count(&self) -> usize { self.accounts.len() }
+    /// Number of recorded deposits, withdrawals and transfers; disputes, resolves and chargebacks add
+    /// no history.
+    pub fn transaction_count(&self) -> usize { self.tx_history.len() }
+    pub fn duplicate_count(&self) -> usize { self.duplicates.load(Ordering::Relaxed) }
+    /// Applies a single event, for callers driving the processor one transaction at a time. A failure
+    /// comes back as a `RowError` carrying the event's tx, type and `line` (0 if unset) and is not
+    /// added to `errors`. May be called from several threads at once, though events for one client
+    /// only make sense in the order they happened.
+    pub fn apply(&self, ev: TxEvent) -> Result<(), RowError> {
+        let (line, tx, kind) = (ev.line.unwrap_or(0), ev.tx, ev.kind);
+        self.process(ev).map_err(|err| RowError::new(line, Some(tx), Some(kind), err))
+    }
     pub fn process(&self, ev: TxEvent) -> Result<(), Box<dyn Error>>{
+        if !self.config.selects_client(ev.client) {
+            return Ok(());
+        }
+        if self.config.idempotent && self.seen.contains(&(ev.kind, ev.tx)) {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            warn!("Skipping redelivered {:?} for tx {}", ev.kind, ev.tx);
+            return Ok(());
+        }
+        if self.config.require_monotonic_tx
+            && matches!(ev.kind, TxKindRaw::Deposit | TxKindRaw::Withdrawal)
+            && let Some(previous) = self.last_txs.get(&ev.client).map(|last| *last)
+            && ev.tx <= previous
+        {
+            return Err(Box::new(ProcessorError::NonMonotonicTx { tx: ev.tx, previous }));
+        }
+        if let Some(rule) = &self.config.freeze_rule
+            && let Some(key) = self.account_key_of(&ev)?
+        {
+            let account = self.accounts.get(&key).map(|account| account.clone()).unwrap_or_default();
+            if rule.call(&account, &ev) {
+                warn!("Freezing client {} and skipping {:?} tx {}", key.0, ev.kind, ev.tx);
+                self.accounts.entry(key.clone()).or_default().lock();
+                return self.record_freeze(&ev, key);
+            }
+        }
+        let (client, tx, kind) = (ev.client, ev.tx, ev.kind);
+        if !self.config.strict_invariants && !self.config.trace && !self.config.record_deltas {
+            self.dispatch(ev)?;
+            self.mark_applied(client, kind, tx);
+            return Ok(());
+        }
+        let key = self.account_key_of(&ev)?;
+        let before = key.as_ref().map(|key| self.account_or_default(key));
+        self.dispatch(ev)?;
+        self.mark_applied(client, kind, tx);
+        if let (Some(key), Some(before)) = (key, before) {
+            if self.config.strict_invariants {
+                self.check_total(tx, kind, &key, before.total())?;
+            }
+            if self.config.record_deltas {
+                self.record_delta(tx, kind, &key, &before)?;
+            }
+            if self.config.trace {
+                self.record_trace(tx, kind, key)?;
+            }
+        }
+        Ok(())
+    }
+    /// Only an event that applied counts as delivered, so a redelivery of one that failed is retried,
+    /// and only its tx id raises the client's high-water mark for `require_monotonic_tx`.
+    fn mark_applied(&self, client: ClientId, kind: TxKindRaw, tx: TxId) {
+        if self.config.idempotent {
+            self.seen.insert((kind, tx));
+        }
+        if self.config.require_monotonic_tx && matches!(kind, TxKindRaw::Deposit | TxKindRaw::Withdrawal) {
+            self.last_txs.insert(client, tx);
+        }
+    }
+    fn account_or_default(&self, key: &AccountKey) -> Account {
+        self.accounts.get(key).map(|account| account.clone()).unwrap_or_default()
+    }
+    fn record_delta(&self, tx: TxId, kind: TxKindRaw, key: &AccountKey, before: &Account) -> Result<(), Box<dyn Error>> {
+        let after = self.account_or_default(key);
+        self.deltas
+            .lock()
+            .map_err(|_| "delta list lock poisoned")?
+            .push(AccountDelta {
+                tx,
+                kind,
+                client: key.0,
+                currency: key.1.clone(),
+                available_delta: after.available() - before.available(),
+                held_delta: after.held() - before.held(),
+                was_locked: before.locked(),
+                locked: after.locked()
+            });
+        Ok(())
+    }
+    /// Events whose account was never created, such as a first withdrawal, are traced at zero.
+    fn record_trace(&self, tx: TxId, kind: TxKindRaw, key: AccountKey) -> Result<(), Box<dyn Error>> {
+        let account = self.account_or_default(&key);
+        self.trace
+            .lock()
+            .map_err(|_| "trace lock poisoned")?
+            .push(TraceRow { tx, kind, client: key.0, currency: key.1, available: account.available(), held: account.held() });
+        Ok(())
+    }
+    /// The account an event acts on; disputes, resolves and chargebacks find it through the history.
+    /// Keeps a frozen event in the history so a replay locks the account as well: a deposit,
+    /// withdrawal or transfer as a rejected record, and anything else by marking the record it names.
+    fn record_freeze(&self, ev: &TxEvent, key: AccountKey) -> Result<(), Box<dyn Error>> {
+        let mut record = match ev.kind {
+            TxKindRaw::Deposit | TxKindRaw::Withdrawal | TxKindRaw::Transfer => {
+                let kind = if ev.kind == TxKindRaw::Deposit { RecordKind::Deposit } else { RecordKind::Withdrawal };
+                let mut record = TxRecord::new(key.0, ev.amount.unwrap_or_default(), false, kind)
+                    .with_currency(key.1)
+                    .with_to_client(ev.to_client)
+                    .with_timestamp(ev.timestamp)
+                    .with_line(ev.line);
+                record.reject();
+                record
+            }
+            _ => match self.tx_history.get(ev.tx)? {
+                Some(record) => record,
+                None => return Ok(())
+            }
+        };
+        record.freeze();
+        self.tx_history.insert(ev.tx, record)
+    }
+    pub(crate) fn account_key_of(&self, ev: &TxEvent) -> Result<Option<AccountKey>, Box<dyn Error>> {
+        match ev.kind {
+            TxKindRaw::Deposit | TxKindRaw::Withdrawal | TxKindRaw::Transfer => Ok(Some((ev.client, ev.currency.clone()))),
+            _ => Ok(self.tx_history.get(ev.tx)?.map(|record| record.account_key()))
+        }
+    }
+    fn dispatch(&self, ev: TxEvent) -> Result<(), Box<dyn Error>> {
         match ev.kind {
             TxKindRaw::Deposit => self.deposit(ev)?,
             TxKindRaw::Withdrawal => self.withdrawal(ev)?,
             TxKindRaw::Dispute => self.dispute(ev)?,
             TxKindRaw::Resolve => self.resolve(ev)?,
-            TxKindRaw::Chargeback => self.chargeback(ev)?
+            TxKindRaw::Chargeback => self.chargeback(ev)?,
+            TxKindRaw::Transfer => self.transfer(ev)?
+        }
+        Ok(())
+    }
+    fn total_of(&self, key: &AccountKey) -> Decimal {
+        self.accounts.get(key).map_or(dec!(0), |account| account.total())
+    }
+    /// Withdrawals and chargebacks may lower the total, as may a resolve under `HoldCredit`, which
+    /// releases the credit its dispute held. Anything else lowering it is recorded as a violation.
+    fn check_total(&self, tx: TxId, kind: TxKindRaw, key: &AccountKey, before: Decimal) -> Result<(), Box<dyn Error>> {
+        let after = self.total_of(key);
+        let may_decrease = match kind {
+            TxKindRaw::Withdrawal | TxKindRaw::Chargeback | TxKindRaw::Transfer => true,
+            TxKindRaw::Resolve => self.config.withdrawal_dispute_policy == WithdrawalDisputePolicy::HoldCredit,
+            TxKindRaw::Deposit | TxKindRaw::Dispute => false
+        };
+        if after < before && !may_decrease {
+            warn!("Invariant violated by {:?} tx {}: total fell from {} to {}", kind, tx, before, after);
+            self.invariant_violations
+                .lock()
+                .map_err(|_| "invariant violation list lock poisoned")?
+                .push(InvariantViolation { tx, kind, client: key.0, before, after });
+        }
+        Ok(())
+    }
+    fn required_amount(&self, event: &TxEvent, action: &str) -> Result<Option<Decimal>, Box<dyn Error>> {
+        match (event.amount, self.config.empty_amount_as) {
+            (Some(a), _) => Ok(Some(a)),
+            (None, EmptyAmount::Error) => Err(From::from(format!("No value amount to {} for tx {}", action, event.tx))),
+            (None, EmptyAmount::Zero) => Ok(Some(dec!(0))),
+            (None, EmptyAmount::Skip) => {
+                warn!("Skipping tx {}: no value amount to {}", event.tx, action);
+                Ok(None)
+            }
+        }
+    }
+    /// Applies `locked_behavior` to a deposit or withdrawal, returning true when it was handled here.
+    fn divert_locked(&self, event: &TxEvent, client: ClientId) -> Result<bool, Box<dyn Error>> {
+        if self.config.locked_behavior == LockedBehavior::DepositsOnly {
+            return Ok(false);
+        }
+        let key = (client, event.currency.clone());
+        if !self.accounts.get(&key).is_some_and(|account| account.locked()) {
+            return Ok(false);
+        }
+        match self.config.locked_behavior {
+            LockedBehavior::DepositsOnly => Ok(false),
+            LockedBehavior::Drop => {
+                warn!("Dropping {:?} tx {}: client {} is locked", event.kind, event.tx, client);
+                Ok(true)
+            }
+            LockedBehavior::Defer => {
+                self.deferred.entry(key).or_default().push(event.clone());
+                Ok(true)
+            }
+            LockedBehavior::Reject => {
+                Err(From::from(format!("Rejecting {:?} tx {}: client {} is locked", event.kind, event.tx, client)))
+            }
+        }
+    }
+    /// Fails a credit of `amount` to `client` that would take its available balance over `max_balance`.
+    fn check_balance_cap(&self, event: &TxEvent, client: ClientId, amount: Decimal, action: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(cap) = self.config.max_balance {
+            let available = self.accounts.get(&(client, event.currency.clone())).map_or(dec!(0), |account| account.available());
+            if available + amount > cap {
+                return Err(From::from(format!("Rejecting {} tx {}: client {} would exceed the balance cap of {}", action, event.tx, client, cap)));
+            }
         }
         Ok(())
     }
     fn deposit(&self, event: TxEvent) -> Result<(), Box<dyn Error>> {
-        let amount = match event.amount {
-            None => {return Err(From::from(format!("No value amount to deposit for tx {}", event.tx)));}
+        if self.divert_locked(&event, event.client)? {
+            return Ok(());
+        }
+        let amount = match self.required_amount(&event, "deposit")? {
+            None => return Ok(()),
             Some(a) => a
         };
+        self.check_balance_cap(&event, event.client, amount, "deposit")?;
 
-        self.accounts.entry(event.client)
-            .and_modify(|existing| {
-                existing.modify_available(&event.amount.unwrap_or(dec!(0)), &RecordKind::Deposit)
-            })
-            .or_insert({
-                let mut acc = Account::new();
-                acc.modify_available(&event.amount.unwrap_or(dec!(0)), &RecordKind::Deposit);
-                acc
-            });
-        self.tx_history.insert(event.tx, TxRecord::new(
+        self.accounts.entry((event.client, event.currency.clone()))
+            .or_default()
+            .apply(&RecordKind::Deposit, AccountAction::Post, &amount, &self.config);
+        self.record_tx(event.tx, TxRecord::new(
             event.client,
             amount,
             false,
             RecordKind::Deposit
-        ));
+        ).with_currency(event.currency).with_timestamp(event.timestamp).with_line(event.line))?;
         Ok(())
     }
     fn withdrawal(&self, event: TxEvent) -> Result<(), Box<dyn Error>> {
-        let amount = match event.amount {
-            None => {return Err(From::from(format!("No value amount to withdraw for tx {}", event.tx)));}
+        if self.divert_locked(&event, event.client)? {
+            return Ok(());
+        }
+        let amount = match self.required_amount(&event, "withdraw")? {
+            None => return Ok(()),
             Some(a) => a
         };
-        self.accounts.entry(event.client)
-            .and_modify(|existing| {
-                if !existing.locked() && existing.available() >= amount {
-                    existing.modify_available(&amount, &RecordKind::Withdrawal)
+        let applied = match self.accounts.entry((event.client, event.currency.clone())) {
+            Entry::Occupied(mut existing) => {
+                if existing.get_mut().apply(&RecordKind::Withdrawal, AccountAction::Post, &amount, &self.config).is_some() {
+                    true
+                } else {
+                    warn!("Skipping withdrawal tx {} for client {}: account locked or insufficient funds", event.tx, event.client);
+                    false
                 }
-            })
-            .or_insert({
-                let mut acc = Account::new();
-                acc.modify_available(&dec!(0), &RecordKind::Deposit);
-                acc
-            });
-        self.tx_history.insert(event.tx, TxRecord::new(
+            }
+            Entry::Vacant(vacant) => {
+                warn!("Skipping withdrawal tx {} for unknown client {}", event.tx, event.client);
+                vacant.insert(Account::default());
+                false
+            }
+        };
+        let mut record = TxRecord::new(
             event.client,
-            event.amount.unwrap(),
+            amount,
             false,
             RecordKind::Withdrawal
-        ));
+        ).with_currency(event.currency).with_timestamp(event.timestamp).with_line(event.line);
+        if !applied {
+            record.reject();
+        }
+        self.record_tx(event.tx, record)?;
         Ok(())
     }
+    /// Moves funds from `client` to `to_client` in the same currency, as a withdrawal from one and a
+    /// deposit to the other: `to_client` must be a selected client, `locked_behavior` applies to
+    /// both accounts and `max_balance` to the destination. Skipped, and recorded as rejected, when
+    /// the source is unknown, locked or short of funds. Transfers are recorded as withdrawals from
+    /// the source carrying `to_client`, and cannot be disputed.
+    fn transfer(&self, event: TxEvent) -> Result<(), Box<dyn Error>> {
+        let amount = match self.required_amount(&event, "transfer")? {
+            None => return Ok(()),
+            Some(a) => a
+        };
+        let to_client = event.to_client.ok_or_else(|| format!("No destination client for transfer tx {}", event.tx))?;
+        if !self.config.selects_client(to_client) {
+            warn!("Skipping transfer tx {}: client {} is not selected", event.tx, to_client);
+            return Ok(());
+        }
+        if self.divert_locked(&event, event.client)? || self.divert_locked(&event, to_client)? {
+            return Ok(());
+        }
+        self.check_balance_cap(&event, to_client, amount, "transfer")?;
+        let debited = self.accounts
+            .get_mut(&(event.client, event.currency.clone()))
+            .is_some_and(|mut source| source.apply(&RecordKind::Withdrawal, AccountAction::Post, &amount, &self.config).is_some());
+        if debited {
+            self.accounts
+                .entry((to_client, event.currency.clone()))
+                .or_default()
+                .apply(&RecordKind::Deposit, AccountAction::Post, &amount, &self.config);
+        } else {
+            warn!("Skipping transfer tx {} from client {}: account unknown, locked or insufficient funds", event.tx, event.client);
+        }
+        let mut record = TxRecord::new(
+            event.client,
+            amount,
+            false,
+            RecordKind::Withdrawal
+        ).with_currency(event.currency).with_to_client(Some(to_client)).with_timestamp(event.timestamp).with_line(event.line);
+        if !debited {
+            record.reject();
+        }
+        self.tx_history.insert(event.tx, record)
+    }
+    fn record_tx(&self, tx: TxId, record: TxRecord) -> Result<(), Box<dyn Error>> {
+        if let Some(window) = self.config.dispute_window {
+            self.recent_txs.entry(*record.client()).or_default().push(tx, window);
+        }
+        self.tx_history.insert(tx, record)
+    }
+    fn within_dispute_window(&self, client: ClientId, tx: TxId) -> bool {
+        match self.config.dispute_window {
+            None => true,
+            Some(_) => self.recent_txs
+                .get(&client)
+                .is_some_and(|recent| recent.members.contains_key(&tx))
+        }
+    }
     fn dispute(&self, ev: TxEvent) -> Result<(), Box<dyn Error>> {
-        match self.tx_history.entry(ev.tx) {
-            Entry::Occupied(mut map_val) => {
-                if !map_val.get().disputed() {
-                    self.accounts
-                        .entry(map_val.get().client().clone())
-                        .and_modify(|existing| {
-                            if !existing.locked() {
-                                existing.dispute_funds(
-                                    &map_val.get().amount(),
-                                    &map_val.get().kind(),
-                                );
-                            }
-                            map_val.get_mut().modify_disputed(true);
-                        });
+        match self.tx_history.get(ev.tx)? {
+            Some(record) if !self.within_dispute_window(*record.client(), ev.tx) => {
+                let window = self.config.dispute_window.unwrap_or_default();
+                self.report_skipped(&ev, ProcessorError::OutsideDisputeWindow { tx: ev.tx, window })?;
+            }
+            Some(record) if *record.charged_back() => {
+                warn!("Ignoring dispute for tx {}: already charged back", ev.tx);
+            }
+            Some(record) if *record.rejected() => {
+                warn!("Ignoring dispute for tx {}: the withdrawal was never applied", ev.tx);
+            }
+            Some(record) if record.to_client().is_some() => {
+                warn!("Ignoring dispute for tx {}: transfers cannot be disputed", ev.tx);
+            }
+            Some(mut record) => {
+                let requested = if self.config.partial_disputes { ev.amount } else { None };
+                let hold = match requested {
+                    None if *record.disputed() => {
+                        if self.config.report_redundant_disputes {
+                            return self.report_skipped(&ev, ProcessorError::AlreadyDisputed { tx: ev.tx });
+                        }
+                        warn!("Ignoring dispute for tx {}: already under dispute", ev.tx);
+                        return Ok(());
+                    }
+                    None => record.disputable(),
+                    Some(partial) if partial <= dec!(0) || partial > record.disputable() => {
+                        warn!("Ignoring dispute for tx {}: {} exceeds the disputable {}", ev.tx, partial, record.disputable());
+                        return Ok(());
+                    }
+                    Some(partial) => partial
+                };
+                let client = *record.client();
+                if let Some(max) = self.config.max_disputes_per_client
+                    && self.dispute_counts.get(&client).is_some_and(|count| *count >= max)
+                {
+                    return Err(Box::new(ProcessorError::TooManyDisputes { client, max }));
                 }
+                self.accounts
+                    .entry(record.account_key())
+                    .and_modify(|existing| {
+                        if let Some(held) = existing.apply(record.kind(), AccountAction::Dispute, &hold, &self.config) {
+                            *self.dispute_counts.entry(client).or_default() += 1;
+                            record.hold_partial(&held);
+                            if held < hold {
+                                *self.dispute_shortfalls.entry(ev.tx).or_default() += hold - held;
+                            }
+                        }
+                    });
+                self.tx_history.insert(ev.tx, record)?;
             }
-            Entry::Vacant(_) => {}
+            None => warn!("Ignoring dispute for unknown tx {}", ev.tx)
         }
         Ok(())
     }
     fn resolve(&self, ev: TxEvent) -> Result<(), Box<dyn Error>> {
-        match self.tx_history.entry(ev.tx) {
-            Entry::Occupied(map_val) => {
-                if map_val.get().disputed().clone() && !map_val.get().charged_back(){
+        match self.tx_history.get(ev.tx)? {
+            Some(mut record) => {
+                if *record.disputed() && !record.charged_back(){
                     self.accounts
-                        .entry(map_val.get().client().clone())
+                        .entry(record.account_key())
                         .and_modify(|existing| {
-                            if !existing.locked() {
-                                existing.resolve_funds(
-                                    &map_val.get().amount(),
-                                    &map_val.get().kind(),
-                                );
+                            if existing.apply(record.kind(), AccountAction::Resolve, record.disputed_amount(), &self.config).is_some() {
+                                record.modify_disputed(false);
                             }
                         });
+                    self.tx_history.insert(ev.tx, record)?;
+                } else {
+                    warn!("Ignoring resolve for tx {}: not under dispute", ev.tx);
                 }
             }
-            Entry::Vacant(_) => {}
+            None => warn!("Ignoring resolve for unknown tx {}", ev.tx)
         }
         Ok(())
     }
     fn chargeback(&self, ev: TxEvent) -> Result<(), Box<dyn Error>> {
-        match self.tx_history.entry(ev.tx) {
-            Entry::Occupied(mut map_val) => {
-                if map_val.get().disputed().clone() && !map_val.get().charged_back() {
-                    self.accounts
-                        .entry(map_val.get().client().clone())
-                        .and_modify(|existing| {
-                            if !existing.locked() {
-                                existing.chargeback_funds(
-                                    &map_val.get().amount(),
-                                    &map_val.get().kind(),
-                                );
-                            }
-                            map_val.get_mut().finish_chargeback();
-                        });
+        match self.tx_history.get(ev.tx)? {
+            Some(mut record) => {
+                if *record.disputed() && !record.charged_back() {
+                    let mut existing = self.accounts.get_mut(&record.account_key());
+                    let mut charged = existing.as_deref().cloned().unwrap_or_default();
+                    if let Some(existing) = existing.as_mut()
+                        && charged.apply(record.kind(), AccountAction::Chargeback, record.disputed_amount(), &self.config).is_some()
+                    {
+                        if self.config.reject_negative_total && charged.total() < dec!(0) {
+                            return Err(From::from(format!("Rejecting chargeback for tx {}: client {} would be left with a negative total", ev.tx, record.client())));
+                        }
+                        **existing = charged;
+                        record.finish_chargeback();
+                    }
+                    self.tx_history.insert(ev.tx, record)?;
+                } else {
+                    warn!("Ignoring chargeback for tx {}: not under dispute", ev.tx);
+                }
+            }
+            None => warn!("Ignoring chargeback for unknown tx {}", ev.tx)
+        }
+        Ok(())
+    }
+}
+
+/// A client's last `dispute_window` deposits and withdrawals, oldest first, with a count per tx id
+/// so membership is checked without scanning the window.
+#[derive(Clone, Default)]
+struct RecentTxs {
+    order: VecDeque<TxId>,
+    members: HashMap<TxId, usize>
+}
+
+impl RecentTxs {
+    fn push(&mut self, tx: TxId, window: usize) {
+        self.order.push_back(tx);
+        *self.members.entry(tx).or_default() += 1;
+        while self.order.len() > window {
+            if let Some(oldest) = self.order.pop_front()
+                && let Some(count) = self.members.get_mut(&oldest)
+            {
+                *count -= 1;
+                if *count == 0 {
+                    self.members.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+impl From<VecDeque<TxId>> for RecentTxs {
+    fn from(order: VecDeque<TxId>) -> RecentTxs {
+        let mut members: HashMap<TxId, usize> = HashMap::new();
+        for tx in &order {
+            *members.entry(*tx).or_default() += 1;
+        }
+        RecentTxs { order, members }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    accounts: Vec<(AccountKey, Account)>,
+    records: Vec<(TxId, TxRecord)>,
+    seen: Vec<(TxKindRaw, TxId)>,
+    duplicates: usize,
+    recent_txs: Vec<(ClientId, VecDeque<TxId>)>,
+    last_txs: Vec<(ClientId, TxId)>,
+    dispute_counts: Vec<(ClientId, usize)>,
+    deferred: Vec<(AccountKey, Vec<TxEvent>)>,
+    dispute_shortfalls: Vec<(TxId, Decimal)>,
+}
+
+fn csv_reader<R: Read>(reader: R, flexible: bool) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .flexible(flexible)
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+}
+
+/// Row error for a record the csv reader could not split, reported on file line `line`.
+fn csv_row_error(err: csv::Error, line: u64) -> RowError {
+    match ProcessorError::from(err) {
+        ProcessorError::InvalidEncoding { .. } => RowError::new(line, None, None, ProcessorError::InvalidEncoding { line }),
+        err => RowError::new(line, None, None, err)
+    }
+}
+
+/// Records in file order; a record the reader fails on is returned with the bytes it covered,
+/// and reading resumes at the next record. Whitespace-only lines are skipped when `skip_blank`.
+fn read_records<R: Read>(rdr: &mut csv::Reader<R>, skip_blank: bool) -> impl Iterator<Item = Result<StringRecord, (csv::Error, Range<u64>)>> + '_ {
+    let mut stuck = false;
+    // fields are trimmed, so a whitespace-only line reads as one empty field
+    let blank = move |record: &StringRecord| skip_blank && record.len() == 1 && record[0].is_empty();
+    iter::from_fn(move || {
+        if stuck {
+            return None;
+        }
+        let mut record = StringRecord::new();
+        loop {
+            let start = rdr.position().byte();
+            match rdr.read_record(&mut record) {
+                Ok(true) if blank(&record) => continue,
+                Ok(true) => return Some(Ok(record)),
+                Ok(false) => return None,
+                Err(err) if matches!(err.kind(), csv::ErrorKind::UnequalLengths { .. }) && blank(&record) => continue,
+                Err(err) => {
+                    let end = rdr.position().byte();
+                    // an error that consumed nothing, such as a failed read, would repeat forever
+                    stuck = end == start;
+                    return Some(Err((err, start..end)));
                 }
             }
-            Entry::Vacant(_) => {}
         }
+    })
+}
+
+/// Reads the header, checking it if configured, and returns it whenever it names the required
+/// columns, so rows are deserialized by column name. A header that does not name them all leaves
+/// rows to be read by position.
+fn read_headers<R: Read>(rdr: &mut csv::Reader<R>, config: &ProcessorConfig) -> Result<Option<StringRecord>, Box<dyn Error>> {
+    let headers = rdr.headers().map_err(ProcessorError::from)?.clone();
+    let unknown = headers.iter().find(|h| !EXPECTED_HEADER.contains(h) && !OPTIONAL_HEADER.contains(h));
+    if config.reject_unknown_columns && let Some(name) = unknown {
+        return Err(Box::new(ProcessorError::UnknownColumn { name: name.to_string() }));
+    }
+    if config.strict_header {
+        check_header(&headers, config.header_any_order)?;
+    }
+    // optional columns such as `timestamp` may come in any subset, and reordered or extra columns
+    // such as `memo` can only be matched up by name
+    let named = EXPECTED_HEADER.iter().all(|expected| headers.iter().any(|h| h == *expected));
+    Ok(named.then_some(headers))
+}
+
+/// Requires every required column to be present and non-empty, found through `headers` when rows
+/// are read by name, and the row to have exactly one field per column.
+fn check_all_fields(record: &StringRecord, headers: Option<&StringRecord>) -> Result<(), ProcessorError> {
+    for (i, column) in EXPECTED_HEADER.iter().enumerate() {
+        let at = match headers {
+            Some(headers) => headers.iter().position(|h| h == *column),
+            None => Some(i)
+        };
+        if at.and_then(|at| record.get(at)).is_none_or(str::is_empty) {
+            return Err(ProcessorError::MissingField { column: column.to_string() });
+        }
+    }
+    let expected = headers.map_or(EXPECTED_HEADER.len(), StringRecord::len);
+    if record.len() != expected {
+        return Err(ProcessorError::FieldCount { expected, found: record.len() });
+    }
+    Ok(())
+}
+
+/// Splits everything after the header into `chunks` pieces at line boundaries and parses them on
+/// separate threads. Each piece is parsed behind a copy of the header so field counts are checked
+/// the same way as the serial reader, and positions are shifted back to file lines and offsets.
+/// Quoted fields spanning lines are not supported.
+fn parse_chunked(input: &[u8], start: &csv::Position, chunks: usize, skip_blank: bool, flexible: bool) -> Vec<Result<StringRecord, RowError>> {
+    let (header, body) = input.split_at(start.byte() as usize);
+    let header_lines = header.iter().filter(|b| **b == b'\n').count() as u64;
+    let mut bounds = vec![0];
+    for i in 1..chunks.max(1) {
+        let mut at = (body.len() * i / chunks).max(bounds[bounds.len() - 1]);
+        while at > 0 && at < body.len() && body[at - 1] != b'\n' {
+            at += 1;
+        }
+        bounds.push(at);
+    }
+    bounds.push(body.len());
+    thread::scope(|scope| {
+        let handles: Vec<_> = bounds.windows(2)
+            .map(|bound| {
+                let (from, to) = (bound[0], bound[1]);
+                let first_line = start.line() + body[..from].iter().filter(|b| **b == b'\n').count() as u64;
+                let line_of = move |pos: &csv::Position| first_line + pos.line() - 1 - header_lines;
+                scope.spawn(move || {
+                    let chunk = [header, &body[from..to]].concat();
+                    let mut rdr = csv_reader(chunk.as_slice(), flexible);
+                    if let Err(err) = rdr.headers() {
+                        return vec![Err(csv_row_error(err, start.line()))];
+                    }
+                    read_records(&mut rdr, skip_blank)
+                        .map(|record| match record {
+                            Ok(mut record) => {
+                                if let Some(mut pos) = record.position().cloned() {
+                                    let (line, byte) = (line_of(&pos), pos.byte() + from as u64);
+                                    pos.set_line(line).set_byte(byte);
+                                    record.set_position(Some(pos));
+                                }
+                                Ok(record)
+                            }
+                            Err((err, bytes)) => {
+                                let line = err.position().map_or(0, line_of);
+                                let bytes = bytes.start + from as u64..bytes.end + from as u64;
+                                Err(csv_row_error(err, line).with_bytes(bytes))
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))).collect()
+    })
+}
+
+const EXPECTED_HEADER: [&str; 4] = ["type", "client", "tx", "amount"];
+/// Columns that may follow the required ones, in this order unless any order is allowed.
+const OPTIONAL_HEADER: [&str; 3] = ["currency", "to_client", "timestamp"];
+
+fn check_header(headers: &StringRecord, any_order: bool) -> Result<(), ProcessorError> {
+    let found: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    let mut columns: Vec<&str> = found.iter().map(|h| h.as_str()).collect();
+    // the required columns, then whichever optional ones the file has, in their usual order
+    let mut expected = EXPECTED_HEADER.to_vec();
+    expected.extend(OPTIONAL_HEADER.iter().filter(|h| columns.contains(h)));
+    if any_order {
+        columns.sort_unstable();
+        expected.sort_unstable();
+    }
+    if columns == expected {
         Ok(())
+    } else {
+        Err(ProcessorError::BadHeader { found })
+    }
+}
+
+/// Token bucket holding at most one token, so rows are spaced evenly rather than bursting.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    fn new(rows_per_sec: u32) -> TokenBucket {
+        TokenBucket {
+            rate: f64::from(rows_per_sec.max(1)),
+            tokens: 1.0,
+            last: Instant::now()
+        }
+    }
+    fn acquire(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(1.0);
+        self.last = now;
+        if self.tokens < 1.0 {
+            thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+            self.last = Instant::now();
+            self.tokens = 1.0;
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+/// Retries reads failing with `Interrupted` or `WouldBlock` up to `attempts` times in a row, waiting
+/// `backoff` and then twice as long after each further failure.
+struct RetryReader<R> {
+    inner: R,
+    attempts: usize,
+    backoff: Duration
+}
+
+impl<R: Read> Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut wait = self.backoff;
+        for _ in 0..self.attempts {
+            match self.inner.read(buf) {
+                Err(err) if matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock) => {
+                    warn!("Retrying read after {}", err);
+                    thread::sleep(wait);
+                    wait *= 2;
+                }
+                result => return result
+            }
+        }
+        self.inner.read(buf)
     }
 }
 
 #[cfg(test)]
 mod process_file_tests {
+    use std::collections::HashSet;
+    use std::error::Error;
     use std::ffi::OsString;
-    use rust_decimal::dec;
+    use std::io::{self, Read};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use rust_decimal::{dec, Decimal};
+    use crate::config::{AccountCallback, AmountSanitizer, DepositDisputePolicy, EmptyAmount, FreezeRule, LockedBehavior, ProcessorConfig};
+    use crate::error::HeldRatioAlert;
+    use crate::model::{Account, AccountDelta, AccountDiff, AccountReport, ClientId, Summary, Timestamp, TxEvent, TxId, TxKindRaw};
     use crate::Processor;
 
     #[test]
@@ -170,8 +1163,8 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_base_data.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        assert_eq!(result.accounts().get(&1).unwrap().available(), dec!(1.5));
-        assert_eq!(result.accounts().get(&2).unwrap().available(), dec!(2));
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.5));
+        assert_eq!(result.account(2).unwrap().available(), dec!(2));
     }
 
     #[test]
@@ -179,7 +1172,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_locked.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(0.5));
@@ -194,7 +1187,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_data_early_lock.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(2.0));
@@ -208,7 +1201,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_data_dispute_resolve.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(1.5));
@@ -222,13 +1215,33 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_over_withdrawal.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(3.0));
         assert_eq!(client_one.held(), dec!(0));
         assert_eq!(client_one.total(), dec!(3.0));
         assert!(!client_one.locked());
+        assert!(result.tx_history().get(4).unwrap().unwrap().rejected());
+        assert!(result.tx_history().get(5).unwrap().unwrap().rejected());
+    }
+
+    fn process_exact_withdrawal(min_balance: Option<Decimal>) -> Processor {
+        let config = ProcessorConfig {
+            min_balance,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 0.1\ndeposit, 1, 2, 0.2\nwithdrawal, 1, 3, 0.3000\n";
+        Processor::process_str(data, config).unwrap()
+    }
+
+    #[test]
+    fn run_test_exact_balance_withdrawal() {
+        let emptied = process_exact_withdrawal(None).account(1).unwrap();
+        assert_eq!((emptied.available(), emptied.total()), (dec!(0), dec!(0)));
+        let kept = process_exact_withdrawal(Some(dec!(0.01))).account(1).unwrap();
+        assert_eq!((kept.available(), kept.total()), (dec!(0.3), dec!(0.3)));
+        assert!(process_exact_withdrawal(Some(dec!(0))).account(1).unwrap().available().is_zero());
     }
 
     #[test]
@@ -236,7 +1249,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_dispute_withdrawal.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(3.0));
@@ -250,7 +1263,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_dispute_withdrawal_resolve.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(1.500));
@@ -265,7 +1278,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_dispute_withdrawal_chargeback.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(4));
@@ -279,7 +1292,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_dispute_chargeback_after_resolve.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(3));
@@ -293,7 +1306,7 @@ mod process_file_tests {
         let result = Processor::process_file(OsString::from("src/transaction_test_data/test_resolve_no_dispute.csv"));
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
+        let client_one = result.account(1);
         assert!(client_one.is_some());
         let client_one = client_one.unwrap().clone();
         assert_eq!(client_one.available(), dec!(1.5));
@@ -303,16 +1316,1935 @@ mod process_file_tests {
     }
 
     #[test]
-    fn run_test_chargeback_no_dispute() {
-        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_chargeback_no_dispute.csv"));
+    fn run_test_currency_amounts_sanitized() {
+        let config = ProcessorConfig {
+            sanitize_amounts: Some(AmountSanitizer::default()),
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_currency_amounts.csv"), config);
         assert!(result.is_ok());
         let result = result.unwrap();
-        let client_one = result.accounts().get(&1);
-        assert!(client_one.is_some());
-        let client_one = client_one.unwrap().clone();
-        assert_eq!(client_one.available(), dec!(1.5));
-        assert_eq!(client_one.held(), dec!(0));
-        assert_eq!(client_one.total(), dec!(1.5));
-        assert!(!client_one.locked());
+        assert_eq!(result.account(1).unwrap().available(), dec!(234.56));
+        assert_eq!(result.account(2).unwrap().available(), dec!(2));
+    }
+
+    #[test]
+    fn run_test_currency_amounts_rejected_by_default() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_currency_amounts.csv"));
+        assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn run_test_final_account_callback() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let config = ProcessorConfig {
+            on_account_final: Some(AccountCallback::new(move |key, account| {
+                sink.lock().unwrap().push((key, account.available()));
+            })),
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_base_data.csv"), config);
+        assert!(result.is_ok());
+        let mut reported = reported.lock().unwrap().clone();
+        reported.sort();
+        assert_eq!(reported, vec![((1, None), dec!(1.5)), ((2, None), dec!(2))]);
+    }
+
+    #[test]
+    fn run_test_final_account_callback_once_across_files() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let sink = calls.clone();
+        let config = ProcessorConfig {
+            on_account_final: Some(AccountCallback::new(move |key, account| {
+                sink.lock().unwrap().push((key, account.available()));
+            })),
+            ..Default::default()
+        };
+        let paths = [
+            PathBuf::from("src/transaction_test_data/test_base_data.csv"),
+            PathBuf::from("src/transaction_test_data/test_currencies.csv")
+        ];
+        let result = Processor::process_files(&paths, config).unwrap();
+        let mut calls = calls.lock().unwrap().clone();
+        calls.sort();
+        assert_eq!(calls.len(), result.account_count());
+        calls.dedup_by_key(|(key, _)| key.clone());
+        assert_eq!(calls.len(), result.account_count());
+        assert!(calls.contains(&((1, None), dec!(1.5))));
+    }
+
+    #[cfg(feature = "disk-history")]
+    #[test]
+    fn run_test_disk_history_matches_memory() {
+        let fixture = "src/transaction_test_data/test_dispute_chargeback_after_resolve.csv";
+        let history_path = std::env::temp_dir().join(format!("csv-txn-history-{}.log", std::process::id()));
+        let config = ProcessorConfig {
+            history_path: Some(history_path.clone()),
+            ..Default::default()
+        };
+        let on_disk = Processor::process_file_with_config(OsString::from(fixture), config);
+        let in_memory = Processor::process_file(OsString::from(fixture)).unwrap();
+        std::fs::remove_file(&history_path).unwrap();
+        let on_disk = on_disk.unwrap();
+        assert_eq!(on_disk.tx_history().len(), in_memory.tx_history().len());
+        for account in in_memory.accounts().iter() {
+            let disk_account = on_disk.accounts().get(account.key()).unwrap().clone();
+            assert_eq!(disk_account.available(), account.available());
+            assert_eq!(disk_account.held(), account.held());
+            assert_eq!(disk_account.locked(), account.locked());
+        }
+    }
+
+    #[test]
+    fn run_test_idempotent_replay() {
+        let config = ProcessorConfig {
+            idempotent: true,
+            ..Default::default()
+        };
+        let replayed = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_replayed_stream.csv"), config);
+        assert!(replayed.is_ok());
+        let replayed = replayed.unwrap();
+        let single = Processor::process_file(OsString::from("src/transaction_test_data/test_data_dispute_resolve.csv")).unwrap();
+        assert_eq!(replayed.duplicate_count(), 7);
+        for account in single.accounts().iter() {
+            let replayed_account = replayed.accounts().get(account.key()).unwrap().clone();
+            assert_eq!(replayed_account.available(), account.available());
+            assert_eq!(replayed_account.held(), account.held());
+            assert_eq!(replayed_account.locked(), account.locked());
+        }
+    }
+
+    #[test]
+    fn run_test_idempotent_skips_redispute() {
+        let config = ProcessorConfig {
+            idempotent: true,
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_redispute_after_resolve.csv"), config).unwrap();
+        let client_one = result.account(1).unwrap();
+        assert_eq!(client_one.available(), dec!(3.0));
+        assert_eq!(client_one.held(), dec!(0));
+        assert!(!result.tx_history().get(1).unwrap().unwrap().disputed());
+        assert_eq!(result.duplicate_count(), 2);
+    }
+
+    #[test]
+    fn run_test_counts() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_data_dispute_resolve.csv"));
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        assert_eq!(result.account_count(), 2);
+        assert_eq!(result.transaction_count(), 5);
+    }
+
+    #[test]
+    fn run_test_max_rows() {
+        let config = ProcessorConfig {
+            max_rows: Some(3),
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_base_data.csv"), config);
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        assert_eq!(result.transaction_count(), 3);
+        assert_eq!(result.account(1).unwrap().available(), dec!(3));
+        assert_eq!(result.account(2).unwrap().available(), dec!(2));
+    }
+
+    #[test]
+    fn run_test_dispute_window() {
+        let config = ProcessorConfig {
+            dispute_window: Some(2),
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_dispute_window.csv"), config);
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        let client_one = result.account(1).unwrap().clone();
+        assert_eq!(client_one.available(), dec!(3.0));
+        assert_eq!(client_one.held(), dec!(3.0));
+        assert!(!result.tx_history().get(1).unwrap().unwrap().disputed());
+        assert!(result.tx_history().get(3).unwrap().unwrap().disputed());
+        let errors = result.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line, errors[0].tx), (5, Some(1)));
+        assert_eq!(errors[0].message, "Tx 1 is older than the client's last 2 transactions and can no longer be disputed");
+    }
+
+    #[test]
+    fn run_test_currencies() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_currencies.csv"));
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        assert_eq!(result.accounts().len(), 2);
+        assert!(result.account(1).is_none());
+        let usd = result.currency_account(1, "USD").unwrap();
+        assert_eq!(usd.available(), dec!(0.5));
+        assert_eq!(usd.held(), dec!(1.0));
+        let eur = result.currency_account(1, "EUR").unwrap();
+        assert_eq!(eur.available(), dec!(3.0));
+        assert_eq!(eur.held(), dec!(0));
+    }
+
+    #[test]
+    fn run_test_redispute_after_resolve() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_redispute_after_resolve.csv"));
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        let client_one = result.account(1).unwrap();
+        assert_eq!(client_one.available(), dec!(2.0));
+        assert_eq!(client_one.held(), dec!(1.0));
+        assert_eq!(client_one.total(), dec!(3.0));
+        assert!(result.tx_history().get(1).unwrap().unwrap().disputed());
+    }
+
+    #[test]
+    fn run_test_dispute_locked() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_dispute_locked.csv"));
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        let client_one = result.account(1).unwrap();
+        assert_eq!(client_one.available(), dec!(2.0));
+        assert_eq!(client_one.held(), dec!(0));
+        assert!(client_one.locked());
+        assert!(!result.tx_history().get(2).unwrap().unwrap().disputed());
+    }
+
+    #[test]
+    fn run_test_rate_limit() {
+        let config = ProcessorConfig {
+            rate_limit: Some(20),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_base_data.csv"), config);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.5));
+        assert_eq!(result.account(2).unwrap().available(), dec!(2));
+    }
+
+    #[test]
+    fn run_test_unexpected_amount() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1, 2.0\n";
+        let lenient = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        assert_eq!(lenient.account(1).unwrap().held(), dec!(5.0));
+        let strict = ProcessorConfig {
+            reject_unexpected_amount: true,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let result = Processor::process_str(data, strict.clone()).unwrap();
+        assert_eq!(result.account(1).unwrap().held(), dec!(0));
+        let errors = result.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line, errors[0].tx), (3, Some(1)));
+        let partial = ProcessorConfig { partial_disputes: true, ..strict };
+        let result = Processor::process_str(data, partial).unwrap();
+        assert_eq!(result.account(1).unwrap().held(), dec!(2.0));
+        assert!(result.errors().is_empty());
+    }
+
+    #[test]
+    fn run_test_dispute_skipped_withdrawal() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 5.0\ndispute, 1, 2,\n";
+        let result = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.0));
+        assert_eq!(result.account(1).unwrap().held(), dec!(0));
+        assert!(result.verify_against_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_test_redundant_dispute() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1,\ndispute, 1, 1,\n";
+        let quiet = Processor::process_str(data, ProcessorConfig { continue_on_error: true, ..Default::default() }).unwrap();
+        assert!(quiet.errors().is_empty());
+        let config = ProcessorConfig {
+            report_redundant_disputes: true,
+            ..Default::default()
+        };
+        let result = Processor::process_str(&format!("{}deposit, 1, 2, 1.0\n", data), config).unwrap();
+        assert_eq!(result.account(1).unwrap().held(), dec!(5.0));
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.0));
+        assert_eq!(result.errors().len(), 1);
+        let config = ProcessorConfig {
+            report_redundant_disputes: true,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().held(), dec!(5.0));
+        assert_eq!(result.account(1).unwrap().available(), dec!(0));
+        let errors = result.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line, errors[0].tx), (4, Some(1)));
+        assert_eq!(errors[0].message, "Tx 1 is already under dispute");
+    }
+
+    #[test]
+    fn run_test_client_filters() {
+        let path = || OsString::from("src/transaction_test_data/test_base_data.csv");
+        let include = ProcessorConfig {
+            include_clients: Some(HashSet::from([2])),
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(path(), include).unwrap();
+        assert!(result.account(1).is_none());
+        assert_eq!(result.account(2).unwrap().available(), dec!(2.0));
+        let exclude = ProcessorConfig {
+            exclude_clients: HashSet::from([2]),
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(path(), exclude).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.5));
+        assert!(result.account(2).is_none());
+        assert_eq!(result.transaction_count(), 3);
+    }
+
+    #[test]
+    fn run_test_timeout() {
+        let rows: String = (1..=100_000).map(|tx| format!("deposit, 1, {}, 1.0\n", tx)).collect();
+        let data = format!("type, client, tx, amount\n{}", rows);
+        let config = ProcessorConfig {
+            timeout: Some(Duration::from_millis(20)),
+            rate_limit: Some(1000),
+            ..Default::default()
+        };
+        let result = Processor::process_str(&data, config.clone()).unwrap();
+        assert!(result.timed_out());
+        let applied = result.transaction_count();
+        assert!(applied > 0 && applied < 100_000);
+        assert_eq!(result.account(1).unwrap().available(), Decimal::from(applied));
+        let atomic = ProcessorConfig { atomic: true, ..config };
+        assert!(Processor::process_str(&data, atomic).is_err());
+        let untimed = Processor::process_str("type, client, tx, amount\ndeposit, 1, 1, 1.0\n", ProcessorConfig {
+            timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        }).unwrap();
+        assert!(!untimed.timed_out());
+    }
+
+    fn process_empty_amount(empty_amount_as: EmptyAmount) -> Result<Processor, Box<dyn Error>> {
+        let config = ProcessorConfig {
+            empty_amount_as,
+            ..Default::default()
+        };
+        Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_empty_amount.csv"), config)
+    }
+
+    #[test]
+    fn run_test_empty_amount_error() {
+        assert!(process_empty_amount(EmptyAmount::Error).is_err());
+    }
+
+    #[test]
+    fn run_test_empty_amount_zero() {
+        let result = process_empty_amount(EmptyAmount::Zero).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(2.0));
+        assert_eq!(result.transaction_count(), 2);
+        assert_eq!(result.tx_history().get(2).unwrap().unwrap().amount(), &dec!(0));
+    }
+
+    #[test]
+    fn run_test_empty_amount_skip() {
+        let result = process_empty_amount(EmptyAmount::Skip).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(2.0));
+        assert_eq!(result.transaction_count(), 1);
+        assert!(result.tx_history().get(2).unwrap().is_none());
+    }
+
+    fn process_partial_disputes(path: &str) -> Processor {
+        let config = ProcessorConfig {
+            partial_disputes: true,
+            ..Default::default()
+        };
+        Processor::process_file_with_config(OsString::from(path), config).unwrap()
+    }
+
+    #[test]
+    fn run_test_partial_dispute_resolve() {
+        let result = process_partial_disputes("src/transaction_test_data/test_partial_dispute_resolve.csv");
+        let client_one = result.account(1).unwrap();
+        assert_eq!(client_one.available(), dec!(10.0));
+        assert_eq!(client_one.held(), dec!(0));
+        assert!(!client_one.locked());
+        assert!(result.verify_against_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_test_partial_dispute_chargeback() {
+        let result = process_partial_disputes("src/transaction_test_data/test_partial_dispute_chargeback.csv");
+        let client_one = result.account(1).unwrap();
+        assert_eq!(client_one.available(), dec!(3.0));
+        assert_eq!(client_one.held(), dec!(0));
+        assert_eq!(client_one.total(), dec!(3.0));
+        assert!(client_one.locked());
+        assert_eq!(result.tx_history().get(1).unwrap().unwrap().disputed_amount(), &dec!(7.0));
+        assert!(result.verify_against_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_test_partial_dispute_amount_ignored_by_default() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_partial_dispute_resolve.csv")).unwrap();
+        let record = result.tx_history().get(1).unwrap().unwrap();
+        assert!(!record.disputed());
+        assert_eq!(result.account(1).unwrap().available(), dec!(10.0));
+    }
+
+    fn process_locked(locked_behavior: LockedBehavior) -> Processor {
+        let config = ProcessorConfig {
+            locked_behavior,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_locked_activity.csv"), config).unwrap()
+    }
+
+    #[test]
+    fn run_test_locked_deposits_only() {
+        let result = process_locked(LockedBehavior::DepositsOnly);
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.0));
+        assert!(result.deferred().is_empty());
+    }
+
+    #[test]
+    fn run_test_locked_drop() {
+        let result = process_locked(LockedBehavior::Drop);
+        assert_eq!(result.account(1).unwrap().available(), dec!(0));
+        assert_eq!(result.transaction_count(), 2);
+        assert!(result.errors().is_empty());
+    }
+
+    #[test]
+    fn run_test_locked_defer() {
+        let result = process_locked(LockedBehavior::Defer);
+        assert_eq!(result.account(1).unwrap().available(), dec!(0));
+        let deferred = result.deferred().get(&(1, None)).unwrap().clone();
+        let deferred: Vec<(TxKindRaw, TxId)> = deferred.iter().map(|ev| (ev.kind, ev.tx)).collect();
+        assert_eq!(deferred, vec![(TxKindRaw::Deposit, 2), (TxKindRaw::Withdrawal, 3)]);
+        assert_eq!(result.account(2).unwrap().available(), dec!(1.0));
+    }
+
+    #[test]
+    fn run_test_locked_reject() {
+        let result = process_locked(LockedBehavior::Reject);
+        assert_eq!(result.account(1).unwrap().available(), dec!(0));
+        let rejected: Vec<Option<TxId>> = result.errors().iter().map(|err| err.tx).collect();
+        assert_eq!(rejected, vec![Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn run_test_capped_dispute() {
+        let config = ProcessorConfig {
+            deposit_dispute_policy: DepositDisputePolicy::CapAtAvailable,
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_capped_dispute.csv"), config).unwrap();
+        // only the 4.0 left after the withdrawal was held, and resolving releases just that
+        assert_eq!(result.account(1).unwrap().available(), dec!(4.0));
+        assert_eq!(result.account(1).unwrap().held(), dec!(0));
+        assert_eq!(result.dispute_shortfall(1), Some(dec!(6.0)));
+        assert_eq!(result.account(2).unwrap().available(), dec!(10.0));
+        assert_eq!(result.account(2).unwrap().held(), dec!(5.0));
+        assert_eq!(result.dispute_shortfall(4), None);
+
+        let uncapped = Processor::process_file(OsString::from("src/transaction_test_data/test_capped_dispute.csv")).unwrap();
+        assert_eq!(uncapped.account(1).unwrap().available(), dec!(4.0));
+        assert_eq!(uncapped.dispute_shortfall(1), None);
+    }
+
+    #[test]
+    fn run_test_capped_dispute_held_until_resolve() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 6.0\ndispute, 1, 1,\n";
+        let config = ProcessorConfig {
+            deposit_dispute_policy: DepositDisputePolicy::CapAtAvailable,
+            ..Default::default()
+        };
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(0));
+        assert_eq!(result.account(1).unwrap().held(), dec!(4.0));
+        assert_eq!(result.tx_history().get(1).unwrap().unwrap().disputed_amount(), &dec!(4.0));
+    }
+
+    fn event(kind: TxKindRaw, client: ClientId, tx: TxId, amount: Option<Decimal>) -> TxEvent {
+        TxEvent { kind, client, tx, amount, currency: None, to_client: None, timestamp: None, line: None }
+    }
+
+    #[test]
+    fn run_test_collect_events() {
+        let events = vec![
+            event(TxKindRaw::Deposit, 1, 1, Some(dec!(5.0))),
+            event(TxKindRaw::Deposit, 2, 2, Some(dec!(3.0))),
+            event(TxKindRaw::Withdrawal, 1, 3, Some(dec!(1.5))),
+            event(TxKindRaw::Dispute, 2, 2, None),
+            event(TxKindRaw::Deposit, 1, 4, None),
+        ];
+        let result: Processor = events.into_iter().collect();
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.5));
+        assert_eq!(result.account(2).unwrap().available(), dec!(0));
+        assert_eq!(result.account(2).unwrap().held(), dec!(3.0));
+        let failed: Vec<(u64, Option<TxId>)> = result.errors().iter().map(|err| (err.line, err.tx)).collect();
+        assert_eq!(failed, vec![(5, Some(4))]);
+    }
+
+    #[test]
+    fn run_test_transfer() {
+        let config = ProcessorConfig {
+            strict_header: true,
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_transfer.csv"), config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.0));
+        assert_eq!(result.account(2).unwrap().available(), dec!(3.0));
+        assert!(result.account(3).is_none());
+        assert_eq!(result.transaction_count(), 5);
+        assert!(result.tx_history().get(4).unwrap().unwrap().rejected());
+        assert_eq!(result.tx_history().get(3).unwrap().unwrap().to_client(), &Some(2));
+        assert!(result.verify_against_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_test_record_lines() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_data_dispute_resolve.csv")).unwrap();
+        let mut lines: Vec<(TxId, Option<u64>)> = result.tx_history().records().unwrap()
+            .iter()
+            .map(|(tx, record)| (*tx, *record.line()))
+            .collect();
+        lines.sort();
+        assert_eq!(lines, vec![(1, Some(2)), (2, Some(3)), (3, Some(4)), (4, Some(5)), (5, Some(6))]);
+    }
+
+    #[test]
+    fn run_test_balances_within() {
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_timestamps.csv"), ProcessorConfig::default()).unwrap();
+        let at = |raw: &str| Some(raw.parse::<Timestamp>().unwrap());
+        let balances = |rows: Vec<AccountReport>| -> Vec<(ClientId, Decimal, Decimal)> {
+            rows.iter().map(|row| (row.client, row.available, row.held)).collect()
+        };
+        let window = result.balances_within(at("2024-05-01T00:00:00Z"), at("2024-05-02T23:59:59Z")).unwrap();
+        assert_eq!(balances(window), vec![(1, dec!(7.0), dec!(0)), (2, dec!(0), dec!(4.0))]);
+        let early = result.balances_within(None, at("2024-05-01T09:30:00Z")).unwrap();
+        assert_eq!(balances(early), vec![(1, dec!(10.0), dec!(0))]);
+        let all = result.balances_within(None, None).unwrap();
+        assert_eq!(balances(all), vec![(1, dec!(12.0), dec!(0)), (2, dec!(1.0), dec!(4.0))]);
+    }
+
+    #[test]
+    fn run_test_transfers_apply_in_file_order() {
+        let data = "type, client, tx, amount, currency, to_client\n\
+            deposit, 1, 1, 5.0, ,\n\
+            withdrawal, 2, 2, 1.0, ,\n\
+            transfer, 1, 3, 5.0, , 2\n\
+            withdrawal, 1, 4, 1.0, ,\n\
+            withdrawal, 2, 5, 4.0, ,\n\
+            transfer, 2, 6, 1.0, , 3\n";
+        let result = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(0.0));
+        assert_eq!(result.account(2).unwrap().available(), dec!(0.0));
+        assert_eq!(result.account(3).unwrap().available(), dec!(1.0));
+    }
+
+    #[test]
+    fn run_test_transfer_insufficient_funds() {
+        let data = "type, client, tx, amount, currency, to_client\ndeposit, 1, 1, 1.0, ,\ntransfer, 1, 2, 1.5, , 2\n";
+        let result = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.0));
+        assert!(result.account(2).is_none());
+        let missing = "type, client, tx, amount, currency, to_client\ndeposit, 1, 1, 1.0, ,\ntransfer, 1, 2, 0.5, ,\n";
+        let err = Processor::process_str(missing, ProcessorConfig::default()).err().unwrap();
+        assert_eq!(err.to_string(), "No destination client for transfer tx 2");
+    }
+
+    #[test]
+    fn run_test_transfer_follows_deposit_rules() {
+        let data = "type, client, tx, amount, currency, to_client\n\
+            deposit, 1, 1, 5.0, ,\n\
+            deposit, 2, 2, 1.0, ,\n\
+            dispute, 2, 2, , ,\n\
+            chargeback, 2, 2, , ,\n\
+            transfer, 1, 3, 1.0, , 2\n\
+            transfer, 1, 4, 1.0, , 3\n\
+            deposit, 4, 6, 4.0, ,\n\
+            transfer, 1, 5, 3.0, , 4\n";
+        let config = ProcessorConfig {
+            locked_behavior: LockedBehavior::Drop,
+            exclude_clients: HashSet::from([3]),
+            max_balance: Some(dec!(5.0)),
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(5.0));
+        assert_eq!(result.account(2).unwrap().available(), dec!(0));
+        assert!(result.account(3).is_none());
+        assert_eq!(result.account(4).unwrap().available(), dec!(4.0));
+        let failed: Vec<Option<TxId>> = result.errors().iter().map(|err| err.tx).collect();
+        assert_eq!(failed, vec![Some(5)]);
+        assert!(result.verify_against_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_test_transfer_without_currency_column() {
+        let data = "type, client, tx, amount, to_client\ndeposit, 1, 1, 5.0,\ntransfer, 1, 2, 2.0, 2\n";
+        let result = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.0));
+        assert_eq!(result.account(2).unwrap().available(), dec!(2.0));
+        assert_eq!(result.account_count(), 2);
+    }
+
+    #[test]
+    fn run_test_freeze_rule() {
+        let data = "type, client, tx, amount\n\
+            deposit, 1, 1, 50.0\n\
+            deposit, 1, 2, 500.0\n\
+            withdrawal, 1, 3, 10.0\n\
+            deposit, 2, 4, 1000.0\n\
+            deposit, 3, 5, 100.0\n";
+        let config = ProcessorConfig {
+            freeze_rule: Some(FreezeRule::new(|_, event| {
+                event.kind == TxKindRaw::Deposit && event.amount.is_some_and(|amount| amount > dec!(100))
+            })),
+            ..Default::default()
+        };
+        let result = Processor::process_str(data, config).unwrap();
+        let frozen = result.account(1).unwrap();
+        assert_eq!(frozen.available(), dec!(50.0));
+        assert!(frozen.locked());
+        let new_client = result.account(2).unwrap();
+        assert_eq!(new_client.available(), dec!(0));
+        assert!(new_client.locked());
+        assert!(!result.account(3).unwrap().locked());
+        let skipped = result.tx_history().get(2).unwrap().unwrap();
+        assert!(*skipped.rejected() && *skipped.frozen());
+        assert!(result.verify_against_history().unwrap().is_empty());
+    }
+
+    fn process_resolve_after_lock(data: &str, allow_resolve_on_locked: bool) -> Account {
+        let config = ProcessorConfig {
+            allow_resolve_on_locked,
+            ..Default::default()
+        };
+        Processor::process_str(data, config).unwrap().account(1).unwrap()
+    }
+
+    #[test]
+    fn run_test_resolve_after_lock() {
+        let data = std::fs::read_to_string("src/transaction_test_data/test_resolve_after_lock.csv").unwrap();
+        let stranded = process_resolve_after_lock(&data, false);
+        assert_eq!((stranded.available(), stranded.held(), stranded.locked()), (dec!(0.0), dec!(5.0), true));
+        let released = process_resolve_after_lock(&data, true);
+        assert_eq!((released.available(), released.held(), released.locked()), (dec!(5.0), dec!(0.0), true));
+    }
+
+    #[test]
+    fn run_test_second_chargeback_after_lock() {
+        let data = std::fs::read_to_string("src/transaction_test_data/test_resolve_after_lock.csv").unwrap()
+            .replace("resolve, 1, 2,", "chargeback, 1, 2,");
+        let account = process_resolve_after_lock(&data, true);
+        assert_eq!((account.available(), account.held(), account.locked()), (dec!(0.0), dec!(0.0), true));
+        // new disputes stay blocked once locked
+        let redispute = format!("{}dispute, 1, 2,\n", std::fs::read_to_string("src/transaction_test_data/test_resolve_after_lock.csv").unwrap());
+        assert_eq!(process_resolve_after_lock(&redispute, true).held(), dec!(0.0));
+    }
+
+    #[test]
+    fn run_test_into_report() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_currencies.csv")).unwrap();
+        let snapshot = result.snapshot();
+        assert!(!snapshot.is_empty());
+        assert_eq!(result.into_report(), snapshot);
+    }
+
+    #[test]
+    fn run_test_apply() {
+        let processor = Processor::default();
+        processor.apply(event(TxKindRaw::Deposit, 1, 1, Some(dec!(5.0)))).unwrap();
+        processor.apply(event(TxKindRaw::Deposit, 2, 2, Some(dec!(1.0)))).unwrap();
+        processor.apply(event(TxKindRaw::Withdrawal, 1, 3, Some(dec!(2.0)))).unwrap();
+        processor.apply(event(TxKindRaw::Dispute, 2, 2, None)).unwrap();
+        let err = processor.apply(TxEvent { line: Some(7), ..event(TxKindRaw::Deposit, 1, 4, None) }).unwrap_err();
+        assert_eq!((err.line, err.tx, err.kind), (7, Some(4), Some(TxKindRaw::Deposit)));
+        assert!(processor.errors().is_empty());
+        assert_eq!(processor.account(1).unwrap().available(), dec!(3.0));
+        assert_eq!(processor.account(2).unwrap().held(), dec!(1.0));
+        thread::scope(|scope| {
+            for client in 3..7 {
+                let processor = &processor;
+                scope.spawn(move || {
+                    for tx in 0..100 {
+                        processor.apply(event(TxKindRaw::Deposit, client, client as TxId * 1000 + tx, Some(dec!(1)))).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(processor.available_total(), dec!(403.0));
+    }
+
+    #[test]
+    fn run_test_live_snapshot() {
+        let processor = Processor::default();
+        let done = AtomicBool::new(false);
+        let snapshots = thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                let mut snapshots = Vec::new();
+                while !done.load(Ordering::Relaxed) {
+                    snapshots.push(processor.live_snapshot());
+                }
+                snapshots
+            });
+            for tx in 1..=20_000 {
+                processor.process(event(TxKindRaw::Deposit, (tx % 10) as ClientId + 1, tx, Some(dec!(1)))).unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+            reader.join().unwrap()
+        });
+        assert!(!snapshots.is_empty());
+        let mut previous = dec!(0);
+        for snapshot in snapshots {
+            assert!(snapshot.len() <= 10);
+            assert!(snapshot.windows(2).all(|pair| pair[0].client < pair[1].client));
+            let total: Decimal = snapshot.iter().map(|row| row.total).sum();
+            assert!(snapshot.iter().all(|row| row.total == row.available + row.held && row.held == dec!(0)));
+            assert!(total >= previous && total <= dec!(20_000));
+            previous = total;
+        }
+        assert_eq!(processor.available_total(), dec!(20_000));
+    }
+
+    #[test]
+    fn run_test_diff() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 2, 2, 3.0\ndispute, 2, 2,\n";
+        let ours = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        let theirs = Processor::process_str(&data.replace("dispute, 2, 2,\n", "deposit, 3, 3, 1.0\n"), ProcessorConfig::default()).unwrap();
+        assert!(ours.diff(&ours).is_empty());
+        let report = |client, available, held| AccountReport { client, currency: None, available, held, total: dec!(3.0), locked: false };
+        assert_eq!(ours.diff(&theirs), vec![
+            AccountDiff { key: (2, None), ours: Some(report(2, dec!(0.0), dec!(3.0))), theirs: Some(report(2, dec!(3.0), dec!(0))) },
+            AccountDiff { key: (3, None), ours: None, theirs: Some(AccountReport { total: dec!(1.0), ..report(3, dec!(1.0), dec!(0)) }) },
+        ]);
+    }
+
+    #[test]
+    fn run_test_prometheus_metrics() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_data_early_lock.csv")).unwrap();
+        assert_eq!(result.prometheus_metrics(), "\
+            # HELP txn_accounts Accounts, one per client and currency.\n\
+            # TYPE txn_accounts gauge\n\
+            txn_accounts 2\n\
+            # HELP txn_locked_accounts Accounts locked by a chargeback or freeze.\n\
+            # TYPE txn_locked_accounts gauge\n\
+            txn_locked_accounts 1\n\
+            # HELP txn_available_sum Available funds summed across every account.\n\
+            # TYPE txn_available_sum gauge\n\
+            txn_available_sum 4\n\
+            # HELP txn_held_sum Held funds summed across every account.\n\
+            # TYPE txn_held_sum gauge\n\
+            txn_held_sum 0\n");
+    }
+
+    #[test]
+    fn run_test_totals_summary() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_data_early_lock.csv")).unwrap();
+        let summary = result.totals_summary();
+        assert_eq!(summary, Summary {
+            clients: 2,
+            available: dec!(4.0),
+            held: dec!(0),
+            locked: 1,
+        });
+    }
+
+    #[test]
+    fn run_test_balance_totals() {
+        let empty = Processor::process_file(OsString::from("src/transaction_test_data/test_empty.csv")).unwrap();
+        assert_eq!(empty.held_total(), dec!(0));
+        assert_eq!(empty.available_total(), dec!(0));
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_timestamps.csv")).unwrap();
+        assert_eq!(result.held_total(), dec!(4.0));
+        assert_eq!(result.available_total(), dec!(13.0));
+    }
+
+    fn process_chargeback_fee(chargeback_fee: Option<Decimal>) -> Processor {
+        let config = ProcessorConfig {
+            chargeback_fee,
+            ..Default::default()
+        };
+        Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_locked.csv"), config).unwrap()
+    }
+
+    #[test]
+    fn run_test_chargeback_fee() {
+        let without = process_chargeback_fee(None);
+        assert_eq!(without.account(1).unwrap().available(), dec!(0.5));
+        let with = process_chargeback_fee(Some(dec!(0.25)));
+        let client_one = with.account(1).unwrap();
+        assert_eq!(client_one.available(), dec!(0.25));
+        assert_eq!(client_one.held(), dec!(0));
+        assert!(client_one.locked());
+        assert_eq!(with.account(2).unwrap().available(), dec!(2.0));
+        assert!(with.verify_against_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_test_signed_amounts() {
+        let config = ProcessorConfig {
+            signed_amounts: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndeposit, 1, 2, -1.5\nwithdrawal, 2, 3, 2.0\nwithdrawal, 2, 4, -0.5\n";
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.5));
+        assert_eq!(result.account(2).unwrap().available(), dec!(1.5));
+    }
+
+    #[test]
+    fn run_test_require_monotonic_tx() {
+        let config = ProcessorConfig {
+            require_monotonic_tx: true,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 5, 3.0\ndeposit, 2, 2, 1.0\nwithdrawal, 1, 4, 1.0\ndispute, 1, 5,\ndeposit, 1, 6, 1.0\n";
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.0));
+        assert_eq!(result.account(1).unwrap().held(), dec!(3.0));
+        let errors = result.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line, errors[0].tx), (4, Some(4)));
+        assert_eq!(errors[0].message, "Tx 4 is out of order; the client's previous tx was 5");
+    }
+
+    #[test]
+    fn run_test_require_monotonic_tx_after_failure() {
+        let config = ProcessorConfig {
+            require_monotonic_tx: true,
+            max_balance: Some(dec!(5.0)),
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 9, 10.0\ndeposit, 1, 3, 2.0\nwithdrawal, 1, 2, 1.0\nwithdrawal, 1, 4, 1.0\n";
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(1.0));
+        let errors = result.errors();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].tx, Some(9));
+        assert_eq!(errors[1].message, "Tx 2 is out of order; the client's previous tx was 3");
+    }
+
+    #[test]
+    fn run_test_client_id_forms() {
+        let data = "type, client, tx, amount\ndeposit, 01, 1, 1.0\ndeposit, +1, 2, 1.0\ndeposit,  1 , 3, 1.0\ndeposit, 1.0, 4, 1.0\n";
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account_count(), 1);
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.0));
+        let failed: Vec<u64> = result.errors().iter().map(|err| err.line).collect();
+        assert_eq!(failed, vec![5]);
+    }
+
+    #[test]
+    fn run_test_flexible_rows() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1\nresolve, 1, 1\ndeposit, 1, 2, 1.0\ndispute, 1, 2\n";
+        assert!(Processor::process_str(data, ProcessorConfig::default()).is_err());
+        for parse_chunks in [None, Some(2)] {
+            let config = ProcessorConfig {
+                flexible: true,
+                parse_chunks,
+                ..Default::default()
+            };
+            let result = Processor::process_str(data, config).unwrap();
+            assert_eq!(result.account(1).unwrap().available(), dec!(5.0));
+            assert_eq!(result.account(1).unwrap().held(), dec!(1.0));
+        }
+    }
+
+    #[test]
+    fn run_test_require_all_fields() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1\ndispute, 1, 1,\ndispute, 1, 1, 0\n";
+        for flexible in [false, true] {
+            let config = ProcessorConfig {
+                require_all_fields: true,
+                flexible,
+                continue_on_error: true,
+                ..Default::default()
+            };
+            let result = Processor::process_str(data, config).unwrap();
+            let failed: Vec<u64> = result.errors().iter().map(|err| err.line).collect();
+            assert_eq!(failed, vec![3, 4]);
+            // without flexible the csv reader already rejects the short row for its field count
+            assert_eq!(result.errors()[1].message, "Row has no amount field");
+            assert_eq!(result.errors()[0].message == "Row has no amount field", flexible);
+            assert_eq!(result.account(1).unwrap().held(), dec!(5.0));
+        }
+        let config = ProcessorConfig {
+            flexible: true,
+            ..Default::default()
+        };
+        assert!(Processor::process_str(data, config).unwrap().errors().is_empty());
+
+        let long = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1, 0, 0\n";
+        let config = ProcessorConfig {
+            require_all_fields: true,
+            flexible: true,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let result = Processor::process_str(long, config).unwrap();
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(result.errors()[0].message, "Row has 5 fields; expected exactly 4");
+        assert_eq!(result.account(1).unwrap().held(), dec!(0));
+    }
+
+    #[test]
+    fn run_test_require_all_fields_by_name() {
+        let data = "client, type, amount, tx\n1, deposit, 5.0, 1\n1, dispute, , 1\n";
+        let config = ProcessorConfig {
+            require_all_fields: true,
+            strict_header: true,
+            header_any_order: true,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(result.errors()[0].message, "Row has no amount field");
+        assert_eq!(result.account(1).unwrap().available(), dec!(5.0));
+    }
+
+    #[test]
+    fn run_test_max_balance() {
+        let config = ProcessorConfig {
+            max_balance: Some(dec!(3.0)),
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 1, 2, 1.0\ndeposit, 1, 3, 0.5\ndeposit, 2, 4, 4.0\n";
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.0));
+        assert!(result.account(2).is_none());
+        assert_eq!(result.transaction_count(), 2);
+        let failed: Vec<(u64, Option<TxId>)> = result.errors().iter().map(|err| (err.line, err.tx)).collect();
+        assert_eq!(failed, vec![(4, Some(3)), (5, Some(4))]);
+    }
+
+    #[test]
+    fn run_test_idempotent_retries_failed_delivery() {
+        let config = ProcessorConfig {
+            idempotent: true,
+            max_balance: Some(dec!(5.0)),
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndeposit, 1, 2, 1.0\nwithdrawal, 1, 3, 2.0\ndeposit, 1, 2, 1.0\n";
+        let result = Processor::process_str(data, config).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(4.0));
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(result.duplicate_count(), 0);
+    }
+
+    fn process_negative_total(reject_negative_total: bool) -> Processor {
+        let config = ProcessorConfig {
+            reject_negative_total,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 8.0\ndispute, 1, 1,\nchargeback, 1, 1,\n";
+        Processor::process_str(data, config).unwrap()
+    }
+
+    #[test]
+    fn run_test_negative_total() {
+        let allowed = process_negative_total(false);
+        let client_one = allowed.account(1).unwrap();
+        assert_eq!((client_one.available(), client_one.held(), client_one.total()), (dec!(-8.0), dec!(0), dec!(-8.0)));
+        assert!(client_one.locked());
+
+        let rejected = process_negative_total(true);
+        let client_one = rejected.account(1).unwrap();
+        assert_eq!((client_one.available(), client_one.held(), client_one.total()), (dec!(-8.0), dec!(10.0), dec!(2.0)));
+        assert!(!client_one.locked());
+        let failed: Vec<(u64, Option<TxId>)> = rejected.errors().iter().map(|err| (err.line, err.tx)).collect();
+        assert_eq!(failed, vec![(5, Some(1))]);
+        assert!(rejected.verify_against_history().unwrap().is_empty());
+    }
+
+    /// Reads the first `fail_at` bytes of `data`, fails the next `failures` reads with `kind`, then
+    /// reads the rest.
+    struct FlakyReader {
+        data: &'static [u8],
+        fail_at: usize,
+        failures: usize,
+        kind: io::ErrorKind
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.fail_at == 0 && self.failures > 0 {
+                self.failures -= 1;
+                return Err(self.kind.into());
+            }
+            let len = if self.fail_at > 0 { buf.len().min(self.fail_at) } else { buf.len() };
+            let read = self.data.read(&mut buf[..len])?;
+            self.fail_at = self.fail_at.saturating_sub(read);
+            Ok(read)
+        }
+    }
+
+    fn read_flaky(failures: usize, kind: io::ErrorKind, read_retries: usize) -> Result<Processor, Box<dyn Error>> {
+        let config = ProcessorConfig {
+            read_retries,
+            read_retry_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let reader = FlakyReader { data: b"type, client, tx, amount\ndeposit, 1, 1, 2.0\n", fail_at: 0, failures, kind };
+        Processor::process_reader(reader, config)
+    }
+
+    #[test]
+    fn run_test_read_retries() {
+        assert!(read_flaky(1, io::ErrorKind::WouldBlock, 0).is_err());
+        assert_eq!(read_flaky(1, io::ErrorKind::WouldBlock, 1).unwrap().account(1).unwrap().available(), dec!(2.0));
+        assert_eq!(read_flaky(3, io::ErrorKind::Interrupted, 3).unwrap().account(1).unwrap().available(), dec!(2.0));
+        assert!(read_flaky(4, io::ErrorKind::Interrupted, 3).is_err());
+        assert!(read_flaky(1, io::ErrorKind::ConnectionReset, 3).is_err());
+    }
+
+    #[test]
+    fn run_test_read_failure_after_header() {
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let data = b"type, client, tx, amount\ndeposit, 1, 1, 2.0\n";
+        let reader = FlakyReader { data, fail_at: 25, failures: 1, kind: io::ErrorKind::ConnectionReset };
+        assert!(Processor::process_reader(reader, config.clone()).is_err());
+        let reader = FlakyReader { data, fail_at: 25, failures: 1, kind: io::ErrorKind::Interrupted };
+        let config = ProcessorConfig { read_retries: 1, read_retry_backoff: Duration::from_millis(1), ..config };
+        assert_eq!(Processor::process_reader(reader, config).unwrap().account(1).unwrap().available(), dec!(2.0));
+    }
+
+    #[test]
+    fn run_test_max_disputes_per_client() {
+        let config = ProcessorConfig {
+            max_disputes_per_client: Some(2),
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\n\
+            deposit, 1, 1, 1.0\ndeposit, 1, 2, 1.0\ndeposit, 1, 3, 1.0\ndeposit, 2, 4, 1.0\n\
+            dispute, 1, 1,\nresolve, 1, 1,\ndispute, 1, 2,\ndispute, 1, 3,\ndispute, 1, 1,\ndispute, 2, 4,\n";
+        let processor = Processor::process_str(data, config).unwrap();
+        let failed: Vec<(u64, Option<TxId>)> = processor.errors().iter().map(|err| (err.line, err.tx)).collect();
+        assert_eq!(failed, vec![(9, Some(3)), (10, Some(1))]);
+        assert_eq!(processor.account(1).unwrap().held(), dec!(1.0));
+        assert_eq!(processor.account(2).unwrap().held(), dec!(1.0));
+    }
+
+    #[test]
+    fn run_test_held_ratio_alerts() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 9.0\ndeposit, 1, 2, 1.0\ndispute, 1, 1,\ndeposit, 2, 3, 10.0\ndeposit, 2, 4, 1.0\ndispute, 2, 4,\n";
+        assert!(Processor::process_str(data, ProcessorConfig::default()).unwrap().held_ratio_alerts().is_empty());
+        let config = ProcessorConfig {
+            max_held_ratio: Some(dec!(2)),
+            ..Default::default()
+        };
+        let processor = Processor::process_str(data, config).unwrap();
+        assert_eq!(processor.held_ratio_alerts(), vec![HeldRatioAlert {
+            client: 1,
+            currency: None,
+            available: dec!(1.0),
+            held: dec!(9.0)
+        }]);
+    }
+
+    #[test]
+    fn run_test_unicode_digit_amounts() {
+        let config = ProcessorConfig {
+            unicode_digits: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, １０．５\nwithdrawal, 1, 2, ٢٫٥\ndeposit, 2, 3, 3.0\n";
+        let processor = Processor::process_str(data, config).unwrap();
+        assert_eq!(processor.account(1).unwrap().available(), dec!(8.0));
+        assert_eq!(processor.account(2).unwrap().available(), dec!(3.0));
+    }
+
+    #[test]
+    fn run_test_deltas() {
+        let config = ProcessorConfig {
+            record_deltas: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1,\nchargeback, 1, 1,\n";
+        let processor = Processor::process_str(data, config).unwrap();
+        let delta = |tx, kind, available_delta, held_delta, locked| AccountDelta {
+            tx,
+            kind,
+            client: 1,
+            currency: None,
+            available_delta,
+            held_delta,
+            was_locked: false,
+            locked
+        };
+        assert_eq!(processor.deltas(), vec![
+            delta(1, TxKindRaw::Deposit, dec!(5.0), dec!(0), false),
+            delta(1, TxKindRaw::Dispute, dec!(-5.0), dec!(5.0), false),
+            delta(1, TxKindRaw::Chargeback, dec!(0), dec!(-5.0), true),
+        ]);
+        assert!(Processor::process_str(data, ProcessorConfig::default()).unwrap().deltas().is_empty());
+    }
+
+    #[test]
+    fn run_test_trace() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndeposit, 1, 2, 2.0\ndispute, 1, 1,\nresolve, 1, 1,\ndispute, 1, 1,\nchargeback, 1, 1,\n";
+        assert!(Processor::process_str(data, ProcessorConfig::default()).unwrap().trace().is_empty());
+        let config = ProcessorConfig {
+            trace: true,
+            ..Default::default()
+        };
+        let trace: Vec<(TxId, TxKindRaw, Decimal, Decimal)> = Processor::process_str(data, config).unwrap()
+            .trace()
+            .into_iter()
+            .map(|row| (row.tx, row.kind, row.available, row.held))
+            .collect();
+        assert_eq!(trace, vec![
+            (1, TxKindRaw::Deposit, dec!(5.0), dec!(0)),
+            (2, TxKindRaw::Deposit, dec!(7.0), dec!(0)),
+            (1, TxKindRaw::Dispute, dec!(2.0), dec!(5.0)),
+            (1, TxKindRaw::Resolve, dec!(7.0), dec!(0)),
+            (1, TxKindRaw::Dispute, dec!(2.0), dec!(5.0)),
+            (1, TxKindRaw::Chargeback, dec!(2.0), dec!(0)),
+        ]);
+    }
+
+    #[test]
+    fn run_test_chargeback_no_dispute() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_chargeback_no_dispute.csv"));
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        let client_one = result.account(1);
+        assert!(client_one.is_some());
+        let client_one = client_one.unwrap().clone();
+        assert_eq!(client_one.available(), dec!(1.5));
+        assert_eq!(client_one.held(), dec!(0));
+        assert_eq!(client_one.total(), dec!(1.5));
+        assert!(!client_one.locked());
+    }
+}
+#[cfg(test)]
+mod logging_tests {
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use crate::config::{ErrorSink, ProcessorConfig};
+    use crate::model::TxId;
+    use crate::Processor;
+
+    struct CapturingLogger {
+        records: Mutex<Vec<(Level, String)>>
+    }
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool { true }
+        fn log(&self, record: &Record) {
+            self.records.lock().unwrap().push((record.level(), record.args().to_string()));
+        }
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger { records: Mutex::new(Vec::new()) };
+
+    fn install_logger() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_logs_malformed_rows() {
+        install_logger();
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            error_sink: ErrorSink::Stderr,
+            ..Default::default()
+        };
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_bad_rows.csv"), config);
+        assert!(result.is_ok());
+        let records = LOGGER.records.lock().unwrap();
+        assert!(records.iter().any(|(level, msg)| {
+            *level == Level::Error && msg == "Row error on line 4: Invalid amount \"abc\" for tx 3"
+        }));
+        assert!(records.iter().any(|(level, msg)| {
+            *level == Level::Error && msg == "Row error on line 3: No value amount to deposit for tx 2"
+        }));
+    }
+
+    /// Runs one bad row with tx id `tx` through `sink` and returns the collected errors and whether
+    /// the row was logged.
+    fn route_bad_row(sink: ErrorSink, tx: TxId) -> (usize, bool) {
+        install_logger();
+        let data = format!("type, client, tx, amount\ndeposit, 1, {}, oops\n", tx);
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            error_sink: sink,
+            ..Default::default()
+        };
+        let processor = Processor::process_str(&data, config).unwrap();
+        let expected = format!("Row error on line 2: Invalid amount \"oops\" for tx {}", tx);
+        let logged = LOGGER.records.lock().unwrap().iter().any(|(level, msg)| *level == Level::Error && *msg == expected);
+        (processor.errors().len(), logged)
+    }
+
+    #[test]
+    fn test_error_sinks() {
+        assert_eq!(route_bad_row(ErrorSink::Collect, 9001), (1, false));
+        assert_eq!(route_bad_row(ErrorSink::Stderr, 9002), (0, true));
+        assert_eq!(route_bad_row(ErrorSink::Both, 9003), (1, true));
+        assert_eq!(route_bad_row(ErrorSink::Silent, 9004), (0, false));
+    }
+
+    #[test]
+    fn test_atomic_without_collected_errors() {
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            atomic: true,
+            error_sink: ErrorSink::Silent,
+            ..Default::default()
+        };
+        let err = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_bad_rows.csv"), config).err().unwrap();
+        assert_eq!(err.to_string(), "2 rows failed");
+    }
+}
+
+#[cfg(test)]
+mod error_report_tests {
+    use std::ffi::OsString;
+    use rust_decimal::dec;
+    use crate::config::ProcessorConfig;
+    use crate::error::{ErrorReportFormat, ProcessorError};
+    use crate::Processor;
+
+    fn process_bad_rows() -> Processor {
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_bad_rows.csv"), config).unwrap()
+    }
+
+    #[test]
+    fn test_aborts_on_bad_row_by_default() {
+        let result = Processor::process_file(OsString::from("src/transaction_test_data/test_bad_rows.csv"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_continues_past_bad_rows() {
+        let processor = process_bad_rows();
+        assert_eq!(processor.account(1).unwrap().available(), dec!(1.5));
+        assert_eq!(processor.errors().len(), 2);
+    }
+
+    const LATIN1_ROW: &[u8] = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0 \xa3\n";
+
+    #[test]
+    fn test_invalid_utf8_row() {
+        let err = Processor::process_reader(LATIN1_ROW, ProcessorConfig::default()).err().unwrap();
+        assert_eq!(err.to_string(), "Invalid UTF-8 on line 3; the input must be UTF-8 encoded");
+        for parse_chunks in [None, Some(2)] {
+            let config = ProcessorConfig {
+                continue_on_error: true,
+                parse_chunks,
+                ..Default::default()
+            };
+            let processor = Processor::process_reader(LATIN1_ROW, config).unwrap();
+            assert_eq!(processor.account(1).unwrap().available(), dec!(1.0));
+            assert_eq!(processor.errors()[0].line, 3);
+        }
+    }
+
+    #[test]
+    fn test_resync_after_corrupt_row() {
+        let data = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndep\xffosit, 1, 2, 2.0\ndeposit, 1, 3, 3.0\n";
+        for parse_chunks in [None, Some(3)] {
+            let config = ProcessorConfig {
+                continue_on_error: true,
+                parse_chunks,
+                ..Default::default()
+            };
+            let processor = Processor::process_reader(&data[..], config).unwrap();
+            assert_eq!(processor.account(1).unwrap().available(), dec!(4.0));
+            let errors = processor.errors();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].line, 3);
+            assert_eq!(errors[0].bytes, Some(44..64));
+        }
+    }
+
+    #[test]
+    fn test_skips_blank_lines() {
+        for parse_chunks in [None, Some(3)] {
+            let config = ProcessorConfig {
+                parse_chunks,
+                ..Default::default()
+            };
+            let processor = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_blank_lines.csv"), config).unwrap();
+            assert_eq!(processor.account(1).unwrap().available(), dec!(2.5));
+            assert!(processor.errors().is_empty());
+        }
+        let config = ProcessorConfig {
+            blank_lines_as_errors: true,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let processor = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_blank_lines.csv"), config).unwrap();
+        assert_eq!(processor.errors().len(), 3);
+    }
+
+    #[test]
+    fn test_blank_skipping_keeps_ragged_rows() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n , , , \ndeposit, 1\n";
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let processor = Processor::process_str(data, config).unwrap();
+        let lines: Vec<u64> = processor.errors().iter().map(|err| err.line).collect();
+        assert_eq!(lines, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_invalid_utf8_header() {
+        let config = ProcessorConfig {
+            strict_header: true,
+            ..Default::default()
+        };
+        let err = Processor::process_reader(&b"type, client, tx, am\xf6unt\n"[..], config).err().unwrap();
+        assert!(matches!(*err.downcast::<ProcessorError>().unwrap(), ProcessorError::InvalidEncoding { line: 1 }));
+    }
+
+    #[test]
+    fn test_csv_error_report() {
+        let processor = process_bad_rows();
+        let mut out = Vec::new();
+        processor.write_errors(&mut out, ErrorReportFormat::Csv).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "line,tx,kind,message\n\
+            3,2,deposit,No value amount to deposit for tx 2\n\
+            4,3,withdrawal,\"Invalid amount \"\"abc\"\" for tx 3\"\n");
+    }
+
+    #[test]
+    fn test_json_error_report() {
+        let processor = process_bad_rows();
+        let mut out = Vec::new();
+        processor.write_errors(&mut out, ErrorReportFormat::Json).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[\
+            {\"line\":3,\"tx\":2,\"kind\":\"deposit\",\"message\":\"No value amount to deposit for tx 2\"},\
+            {\"line\":4,\"tx\":3,\"kind\":\"withdrawal\",\"message\":\"Invalid amount \\\"abc\\\" for tx 3\"}]");
+    }
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use std::ffi::OsString;
+    use std::fs;
+    use rust_decimal::dec;
+    use crate::config::{FreezeRule, ProcessorConfig, WithdrawalDisputePolicy};
+    use crate::error::InvariantViolation;
+    use crate::model::{RecordKind, TxKindRaw};
+    use crate::Processor;
+
+    #[test]
+    fn test_fixtures_match_history() {
+        for entry in fs::read_dir("src/transaction_test_data").unwrap() {
+            let path = entry.unwrap().path();
+            // reuses tx ids, which history replay cannot tell apart
+            if path.ends_with("test_replayed_stream.csv") {
+                continue;
+            }
+            // fixtures with bad rows still have their good rows checked
+            let config = ProcessorConfig { continue_on_error: true, ..Default::default() };
+            let processor = Processor::process_file_with_config(path.clone().into_os_string(), config)
+                .unwrap_or_else(|err| panic!("{:?}: {}", path, err));
+            let discrepancies = processor.verify_against_history().unwrap();
+            assert!(discrepancies.is_empty(), "{:?}: {:?}", path, discrepancies);
+        }
+    }
+
+    #[test]
+    fn test_frozen_accounts_match_history() {
+        let data = "type, client, tx, amount\n\
+            deposit, 1, 1, 50.0\n\
+            deposit, 1, 2, 500.0\n\
+            deposit, 2, 3, 5.0\n\
+            dispute, 2, 3,\n\
+            deposit, 3, 4, 1.0\n";
+        let config = ProcessorConfig {
+            freeze_rule: Some(FreezeRule::new(|_, event| {
+                event.kind == TxKindRaw::Dispute || event.amount.is_some_and(|amount| amount > dec!(100))
+            })),
+            ..Default::default()
+        };
+        let processor = Processor::process_str(data, config).unwrap();
+        assert!(processor.account(1).unwrap().locked());
+        assert!(processor.account(2).unwrap().locked());
+        assert!(!processor.tx_history().get(3).unwrap().unwrap().disputed());
+        assert!(processor.tx_history().get(3).unwrap().unwrap().frozen());
+        assert!(processor.verify_against_history().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_detects_corrupted_account() {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_data_dispute_resolve.csv")).unwrap();
+        processor.accounts().get_mut(&(2, None)).unwrap().modify_available(&dec!(0.01), &RecordKind::Deposit);
+        let discrepancies = processor.verify_against_history().unwrap();
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(discrepancies[0].actual.client, 2);
+        assert_eq!(discrepancies[0].actual.available, dec!(2.01));
+        assert_eq!(discrepancies[0].expected.available, dec!(2.0));
+    }
+
+    fn strict(withdrawal_dispute_policy: WithdrawalDisputePolicy) -> ProcessorConfig {
+        ProcessorConfig {
+            strict_invariants: true,
+            continue_on_error: true,
+            withdrawal_dispute_policy,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fixtures_keep_total_invariant() {
+        for policy in [WithdrawalDisputePolicy::Mirror, WithdrawalDisputePolicy::HoldCredit] {
+            for entry in fs::read_dir("src/transaction_test_data").unwrap() {
+                let path = entry.unwrap().path();
+                if let Ok(processor) = Processor::process_file_with_config(path.clone().into_os_string(), strict(policy)) {
+                    assert!(processor.invariant_violations().is_empty(), "{:?}: {:?}", path, processor.invariant_violations());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_flags_deposit_that_lowers_total() {
+        let processor = Processor::process_file_with_config(
+            OsString::from("src/transaction_test_data/test_base_data.csv"),
+            strict(WithdrawalDisputePolicy::Mirror)
+        ).unwrap();
+        let key = (1, None);
+        let before = processor.total_of(&key);
+        // a broken deposit that debits instead of credits
+        processor.accounts().get_mut(&key).unwrap().modify_available(&dec!(0.5), &RecordKind::Withdrawal);
+        processor.check_total(9, TxKindRaw::Deposit, &key, before).unwrap();
+        assert_eq!(processor.invariant_violations(), vec![InvariantViolation {
+            tx: 9,
+            kind: TxKindRaw::Deposit,
+            client: 1,
+            before: dec!(1.5),
+            after: dec!(1.0)
+        }]);
+    }
+}
+
+#[cfg(test)]
+mod header_tests {
+    use std::ffi::OsString;
+    use rust_decimal::dec;
+    use crate::config::ProcessorConfig;
+    use crate::error::ProcessorError;
+    use crate::model::Timestamp;
+    use crate::Processor;
+
+    fn process_with_header_check(path: &str, any_order: bool) -> Result<Processor, Vec<String>> {
+        let config = ProcessorConfig {
+            strict_header: true,
+            header_any_order: any_order,
+            ..Default::default()
+        };
+        Processor::process_file_with_config(OsString::from(path), config)
+            .map_err(|err| match *err.downcast::<ProcessorError>().unwrap() {
+                ProcessorError::BadHeader { found } => found,
+                other => panic!("unexpected error {:?}", other)
+            })
+    }
+
+    #[test]
+    fn test_expected_header() {
+        assert!(process_with_header_check("src/transaction_test_data/test_base_data.csv", false).is_ok());
+        assert!(process_with_header_check("src/transaction_test_data/test_currencies.csv", false).is_ok());
+    }
+
+    #[test]
+    fn test_reordered_header() {
+        let result = process_with_header_check("src/transaction_test_data/test_reordered_header.csv", false);
+        assert_eq!(result.err(), Some(vec!["client".to_string(), "type".to_string(), "tx".to_string(), "amount".to_string()]));
+        let reordered = process_with_header_check("src/transaction_test_data/test_reordered_header.csv", true).unwrap();
+        assert_eq!(reordered.account(1).unwrap().available(), dec!(1.0));
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let result = process_with_header_check("src/transaction_test_data/test_missing_column.csv", true);
+        assert_eq!(result.err(), Some(vec!["type".to_string(), "client".to_string(), "tx".to_string()]));
+    }
+
+    #[test]
+    fn test_optional_column_subsets() {
+        let header = |columns: &str| format!("type, client, tx, amount{}\n", columns);
+        for columns in [", timestamp", ", to_client", ", currency, timestamp", ", to_client, timestamp"] {
+            let data = header(columns);
+            assert!(Processor::process_str(&data, ProcessorConfig { strict_header: true, ..Default::default() }).is_ok(), "{}", data);
+        }
+        let data = header(", timestamp, currency");
+        let config = ProcessorConfig { strict_header: true, ..Default::default() };
+        assert!(Processor::process_str(&data, config).is_err());
+        let config = ProcessorConfig { strict_header: true, header_any_order: true, ..Default::default() };
+        assert!(Processor::process_str(&data, config).is_ok());
+
+        let data = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 5.0, 2024-05-01T09:00:00Z\n";
+        let result = Processor::process_str(data, ProcessorConfig { strict_header: true, ..Default::default() }).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(5.0));
+        assert!(result.tx_history().get(1).unwrap().unwrap().timestamp().is_some());
+    }
+
+    #[test]
+    fn test_single_optional_column() {
+        let data = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 5.0, 2024-05-01T09:00:00Z\n";
+        let result = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(5.0));
+        let record = result.tx_history().get(1).unwrap().unwrap();
+        assert_eq!(record.currency(), &None);
+        assert_eq!(record.timestamp(), &Some("2024-05-01T09:00:00Z".parse::<Timestamp>().unwrap()));
+
+        let data = "type, client, tx, amount, to_client\ndeposit, 1, 1, 5.0,\ntransfer, 1, 2, 1.5, 2\n";
+        let result = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        assert_eq!(result.account(1).unwrap().available(), dec!(3.5));
+        assert_eq!(result.account(2).unwrap().available(), dec!(1.5));
+        assert_eq!(result.tx_history().get(1).unwrap().unwrap().currency(), &None);
+    }
+
+    #[test]
+    fn test_unknown_column() {
+        let path = "src/transaction_test_data/test_unknown_column.csv";
+        let tolerated = Processor::process_file(OsString::from(path)).unwrap();
+        let client_one = tolerated.account(1).unwrap();
+        assert_eq!(client_one.available(), dec!(1.5));
+        assert_eq!(tolerated.account_count(), 1);
+
+        let config = ProcessorConfig {
+            reject_unknown_columns: true,
+            ..Default::default()
+        };
+        let err = Processor::process_file_with_config(OsString::from(path), config).err().unwrap();
+        match *err.downcast::<ProcessorError>().unwrap() {
+            ProcessorError::UnknownColumn { name } => assert_eq!(name, "memo"),
+            other => panic!("unexpected error {:?}", other)
+        }
+        let config = ProcessorConfig {
+            reject_unknown_columns: true,
+            ..Default::default()
+        };
+        assert!(Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_currencies.csv"), config).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod determinism_tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::thread;
+    use crate::config::ProcessorConfig;
+    use crate::model::AccountReport;
+    use crate::Processor;
+
+    const FIXTURES: [&str; 5] = [
+        "src/transaction_test_data/test_base_data.csv",
+        "src/transaction_test_data/test_data_dispute_resolve.csv",
+        "src/transaction_test_data/test_data_early_lock.csv",
+        "src/transaction_test_data/test_sort_accounts.csv",
+        "src/transaction_test_data/test_currencies.csv",
+    ];
+
+    fn snapshot_of(data: &str) -> Vec<AccountReport> {
+        Processor::process_str(data, ProcessorConfig::default()).unwrap().snapshot()
+    }
+
+    /// Reorders rows across clients with a seeded LCG while keeping each client's rows in file order.
+    fn interleave(data: &str, seed: u64) -> String {
+        let mut lines = data.lines().filter(|line| !line.trim().is_empty());
+        let header = lines.next().unwrap_or_default();
+        let mut by_client: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for line in lines {
+            let client = line.split(',').nth(1).unwrap_or_default().trim().to_string();
+            by_client.entry(client).or_default().push(line);
+        }
+        let mut queues: Vec<Vec<&str>> = by_client.into_values().map(|mut rows| { rows.reverse(); rows }).collect();
+        let mut state = seed;
+        let mut out = vec![header];
+        while !queues.is_empty() {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let pick = (state >> 33) as usize % queues.len();
+            out.push(queues[pick].pop().unwrap());
+            if queues[pick].is_empty() {
+                queues.remove(pick);
+            }
+        }
+        out.join("\n")
+    }
+
+    fn assert_deterministic(path: &str, runs: u64) {
+        let data = fs::read_to_string(path).unwrap();
+        let expected = snapshot_of(&data);
+        let snapshots: Vec<Vec<AccountReport>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..runs)
+                .map(|seed| {
+                    let data = &data;
+                    scope.spawn(move || snapshot_of(&interleave(data, seed)))
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        for (seed, snapshot) in snapshots.iter().enumerate() {
+            assert_eq!(snapshot, &expected, "{} diverged with interleaving seed {}", path, seed);
+        }
+    }
+
+    #[test]
+    fn test_fixtures_are_order_independent_across_clients() {
+        for fixture in FIXTURES {
+            assert_deterministic(fixture, 16);
+        }
+    }
+
+    #[test]
+    fn test_aggregates_are_repeatable() {
+        let aggregates = |data: &str| {
+            let processor = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+            let summary = processor.totals_summary();
+            // compared as text so a change in decimal scale also counts as a difference
+            format!("{:?} {} {} {}", summary, summary.available, processor.held_total(), processor.available_total())
+        };
+        for fixture in FIXTURES {
+            let data = fs::read_to_string(fixture).unwrap();
+            let expected = aggregates(&data);
+            for seed in 0..16 {
+                assert_eq!(aggregates(&interleave(&data, seed)), expected, "{} diverged with interleaving seed {}", fixture, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interleave_keeps_client_order() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1\ndeposit,2,2,1\nwithdrawal,1,3,1\nwithdrawal,2,4,1";
+        for seed in 0..8 {
+            let shuffled = interleave(data, seed);
+            let rows: Vec<&str> = shuffled.lines().collect();
+            let position = |row: &str| rows.iter().position(|r| *r == row).unwrap();
+            assert!(position("deposit,1,1,1") < position("withdrawal,1,3,1"));
+            assert!(position("deposit,2,2,1") < position("withdrawal,2,4,1"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod chunked_read_tests {
+    use std::ffi::OsString;
+    use std::fs;
+    use std::time::Instant;
+    use crate::config::ProcessorConfig;
+    use crate::model::AccountReport;
+    use crate::error::RowError;
+    use crate::Processor;
+
+    fn run(data: &str, parse_chunks: Option<usize>) -> (Vec<AccountReport>, Vec<RowError>) {
+        let config = ProcessorConfig {
+            parse_chunks,
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let processor = Processor::process_str(data, config).unwrap();
+        (processor.snapshot(), processor.errors())
+    }
+
+    /// Deposits, withdrawals and disputes for `clients` clients, picked with a seeded LCG.
+    fn generated(rows: u32, clients: u32) -> String {
+        let mut state = 42u64;
+        let mut out = String::from("type, client, tx, amount\n");
+        for tx in 1..=rows {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let client = (state >> 33) as u32 % clients + 1;
+            let line = match (state >> 20) % 10 {
+                0 => format!("dispute, {}, {},\n", client, (state >> 40) as u32 % tx + 1),
+                1..=3 => format!("withdrawal, {}, {}, {}.{}\n", client, tx, (state >> 8) % 50, state % 10),
+                _ => format!("deposit, {}, {}, {}.{}\n", client, tx, (state >> 8) % 100, state % 100)
+            };
+            out.push_str(&line);
+        }
+        out
+    }
+
+    #[test]
+    fn test_chunked_matches_serial_on_fixtures() {
+        for entry in fs::read_dir("src/transaction_test_data").unwrap() {
+            let path = entry.unwrap().path();
+            let data = fs::read_to_string(&path).unwrap();
+            let serial = run(&data, None);
+            for chunks in 1..=4 {
+                assert_eq!(run(&data, Some(chunks)), serial, "{:?} diverged with {} chunks", path, chunks);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_matches_serial_on_generated_input() {
+        let data = generated(20_000, 50);
+        assert_eq!(run(&data, Some(8)), run(&data, None));
+    }
+
+    #[test]
+    fn test_chunked_reports_file_lines() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\ndeposit, 1, 3, abc\ndeposit, 1\n";
+        let (_, errors) = run(data, Some(3));
+        let lines: Vec<u64> = errors.iter().map(|err| err.line).collect();
+        assert_eq!(lines, vec![4, 5]);
+        assert!(Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_base_data.csv"), ProcessorConfig {
+            parse_chunks: Some(3),
+            ..Default::default()
+        }).is_ok());
+    }
+
+    // cargo test --release chunked_read_tests -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_chunked_vs_serial() {
+        let data = generated(1_000_000, 100);
+        for parse_chunks in [None, Some(2), Some(4), Some(8)] {
+            let started = Instant::now();
+            run(&data, parse_chunks);
+            println!("{:?}: {:?}", parse_chunks, started.elapsed());
+        }
+    }
+
+    #[test]
+    fn test_expected_clients_sizes_accounts() {
+        let config = ProcessorConfig {
+            expected_clients: Some(10_000),
+            ..Default::default()
+        };
+        assert!(Processor::with_config(config).accounts().capacity() >= 10_000);
+    }
+
+    // cargo test --release chunked_read_tests::bench_expected_clients -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_expected_clients() {
+        let data = generated(1_000_000, 60_000);
+        for expected_clients in [None, Some(60_000)] {
+            let config = ProcessorConfig {
+                expected_clients,
+                ..Default::default()
+            };
+            let processor = Processor::with_config(config);
+            let capacity = processor.accounts().capacity();
+            let started = Instant::now();
+            let processor = processor.resume_reader(data.as_bytes()).unwrap();
+            println!("{:?}: {:?}, capacity {} -> {}", expected_clients, started.elapsed(), capacity, processor.accounts().capacity());
+        }
+    }
+}
+
+#[cfg(test)]
+mod checkpoint_tests {
+    use std::fs;
+    use rust_decimal::dec;
+    use crate::config::ProcessorConfig;
+    use crate::model::{AccountReport, TxRecordView};
+    use crate::Processor;
+
+    fn config() -> ProcessorConfig {
+        ProcessorConfig {
+            idempotent: true,
+            dispute_window: Some(2),
+            continue_on_error: true,
+            ..Default::default()
+        }
+    }
+
+    /// Source lines are left out, since a resumed input numbers its lines from its own header.
+    fn state(processor: &Processor) -> (Vec<AccountReport>, Vec<TxRecordView>) {
+        let mut history: Vec<TxRecordView> = processor.tx_history().records().unwrap()
+            .iter()
+            .map(|(tx, record)| TxRecordView { line: None, ..record.view(*tx) })
+            .collect();
+        history.sort_by_key(|view| view.tx);
+        (processor.snapshot(), history)
+    }
+
+    #[test]
+    fn test_restore_and_resume_matches_straight_run() {
+        for entry in fs::read_dir("src/transaction_test_data").unwrap() {
+            let path = entry.unwrap().path();
+            let data = fs::read_to_string(&path).unwrap();
+            let lines: Vec<&str> = data.lines().collect();
+            let Some((header, rows)) = lines.split_first() else { continue };
+            let straight = state(&Processor::process_str(&data, config()).unwrap());
+            for split in 0..=rows.len() {
+                let head = [&[*header], &rows[..split]].concat().join("\n");
+                let tail = [&[*header], &rows[split..]].concat().join("\n");
+                let mut saved = Vec::new();
+                Processor::process_str(&head, config()).unwrap().checkpoint(&mut saved).unwrap();
+                let resumed = Processor::restore(saved.as_slice(), config()).unwrap()
+                    .resume_reader(tail.as_bytes())
+                    .unwrap();
+                assert_eq!(state(&resumed), straight, "{:?} diverged when split after {} rows", path, split);
+            }
+        }
+    }
+
+    #[test]
+    fn test_restore_keeps_duplicate_tracking() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+        let mut saved = Vec::new();
+        Processor::process_str(data, config()).unwrap().checkpoint(&mut saved).unwrap();
+        let resumed = Processor::restore(saved.as_slice(), config()).unwrap().resume_reader(data.as_bytes()).unwrap();
+        assert_eq!(resumed.duplicate_count(), 1);
+        assert_eq!(resumed.account(1).unwrap().available(), dec!(1.0));
+    }
+
+    #[test]
+    fn test_dispute_opened_before_checkpoint_resolves_after_restore() {
+        let head = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndeposit, 1, 2, 3.0\ndispute, 1, 1,\ndispute, 1, 2,\nchargeback, 1, 2,\n";
+        let mut saved = Vec::new();
+        Processor::process_str(head, ProcessorConfig::default()).unwrap().checkpoint(&mut saved).unwrap();
+        let restored = Processor::restore(saved.as_slice(), ProcessorConfig::default()).unwrap();
+        let account = restored.account(1).unwrap();
+        assert_eq!((account.available(), account.held(), account.locked()), (dec!(0), dec!(5.0), true));
+
+        let config = ProcessorConfig {
+            allow_resolve_on_locked: true,
+            ..Default::default()
+        };
+        let tail = "type, client, tx, amount\nresolve, 1, 1,\nresolve, 1, 2,\n";
+        let resumed = Processor::restore(saved.as_slice(), config).unwrap().resume_reader(tail.as_bytes()).unwrap();
+        let account = resumed.account(1).unwrap();
+        assert_eq!((account.available(), account.held(), account.locked()), (dec!(5.0), dec!(0), true));
+        let history = resumed.tx_history().records().unwrap();
+        let (_, first) = history.iter().find(|(tx, _)| *tx == 1).unwrap();
+        let (_, second) = history.iter().find(|(tx, _)| *tx == 2).unwrap();
+        assert!(!*first.disputed() && !*first.charged_back());
+        assert!(*second.charged_back());
+    }
+}
+
+/// Randomized event sequences from a seeded LCG, checking accounting invariants after every event.
+#[cfg(test)]
+mod invariant_property_tests {
+    use std::collections::HashMap;
+    use rust_decimal::Decimal;
+    use crate::config::{LockedBehavior, ProcessorConfig};
+    use crate::model::{ClientId, TxEvent, TxId, TxKindRaw};
+    use crate::Processor;
+
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % bound
+        }
+    }
+
+    /// Deposits and withdrawals take fresh tx ids; disputes, resolves and chargebacks name an earlier
+    /// one, usually with the client that made it.
+    fn events(seed: u64, count: usize) -> Vec<TxEvent> {
+        let mut rng = Lcg(seed);
+        let mut made: Vec<(TxId, ClientId)> = Vec::new();
+        let mut events = Vec::with_capacity(count);
+        for next_tx in 1..=count as TxId {
+            let roll = rng.next(10);
+            let (kind, client, tx, amount) = if made.is_empty() || roll < 6 {
+                let kind = if roll < 4 { TxKindRaw::Deposit } else { TxKindRaw::Withdrawal };
+                let client = rng.next(4) as ClientId + 1;
+                made.push((next_tx, client));
+                (kind, client, next_tx, Some(Decimal::new(rng.next(10_000) as i64 + 1, 2)))
+            } else {
+                let kind = match roll {
+                    6 | 7 => TxKindRaw::Dispute,
+                    8 => TxKindRaw::Resolve,
+                    _ => TxKindRaw::Chargeback
+                };
+                let (tx, client) = made[rng.next(made.len() as u64) as usize];
+                let client = if rng.next(20) == 0 { client % 4 + 1 } else { client };
+                (kind, client, tx, None)
+            };
+            events.push(TxEvent { kind, client, tx, amount, currency: None, to_client: None, timestamp: None, line: None });
+        }
+        events
+    }
+
+    fn fields(processor: &Processor, client: ClientId) -> Option<(Decimal, Decimal, bool)> {
+        processor.account(client).map(|account| (account.available(), account.held(), account.locked()))
+    }
+
+    fn check_sequence(seed: u64, locked_behavior: LockedBehavior) {
+        let processor = Processor::with_config(ProcessorConfig { locked_behavior, ..Default::default() });
+        // (available, held) change applied by the dispute currently open on each tx
+        let mut open_disputes: HashMap<TxId, (Decimal, Decimal)> = HashMap::new();
+        for ev in events(seed, 300) {
+            let (kind, client, tx) = (ev.kind, ev.client, ev.tx);
+            let record = processor.tx_history().get(tx).unwrap();
+            let owner = record.as_ref().map_or(client, |record| *record.client());
+            let before = fields(&processor, owner);
+            let _ = processor.process(ev);
+            let after = fields(&processor, owner);
+            let context = format!("seed {} {:?} tx {}", seed, kind, tx);
+
+            let was_locked = before.is_some_and(|(_, _, locked)| locked);
+            match kind {
+                TxKindRaw::Withdrawal if was_locked => assert_eq!(after, before, "{}", context),
+                TxKindRaw::Deposit if was_locked && locked_behavior == LockedBehavior::Drop => {
+                    assert_eq!(after, before, "{}", context)
+                }
+                _ => {}
+            }
+            let was_disputed = record.as_ref().is_some_and(|record| *record.disputed());
+            let now_disputed = processor.tx_history().get(tx).unwrap().is_some_and(|record| *record.disputed());
+            if let (Some((available, held, _)), Some((available_after, held_after, _))) = (before, after) {
+                let delta = (available_after - available, held_after - held);
+                if kind == TxKindRaw::Dispute && !was_disputed && now_disputed {
+                    open_disputes.insert(tx, delta);
+                }
+                if kind == TxKindRaw::Resolve && was_disputed && !now_disputed {
+                    let opened = open_disputes.remove(&tx).unwrap();
+                    assert_eq!(delta, (-opened.0, -opened.1), "resolve did not undo the dispute, {}", context);
+                }
+            }
+        }
+        for report in processor.snapshot() {
+            assert_eq!(report.total, report.available + report.held, "seed {} {:?}", seed, report);
+        }
+        let discrepancies = processor.verify_against_history().unwrap();
+        assert!(discrepancies.is_empty(), "seed {} disagrees with its history: {:?}", seed, discrepancies);
+    }
+
+    #[test]
+    fn test_invariants_hold_for_random_sequences() {
+        for seed in 0..64 {
+            check_sequence(seed, LockedBehavior::DepositsOnly);
+            check_sequence(seed, LockedBehavior::Drop);
+        }
+    }
+
+    #[test]
+    fn test_generator_covers_every_kind() {
+        let kinds: Vec<TxKindRaw> = events(7, 300).iter().map(|ev| ev.kind).collect();
+        for kind in [TxKindRaw::Deposit, TxKindRaw::Withdrawal, TxKindRaw::Dispute, TxKindRaw::Resolve, TxKindRaw::Chargeback] {
+            assert!(kinds.contains(&kind), "{:?} never generated", kind);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+/// Seeded transaction streams for tests and benchmarks. Clients take turns in round-robin order, each
+/// withdrawing only what it has available and disputing only its own undisputed deposits, so every
+/// row applies cleanly. `dispute_ratio` is the chance, once a client has a deposit to dispute, that its
+/// turn is a dispute.
+pub fn generate_csv(clients: u16, txns_per_client: u32, dispute_ratio: f64) -> String {
+    let mut state = 42u64;
+    let mut next = move || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        state >> 33
+    };
+    // per client: available cents and the (tx, cents) deposits not yet disputed
+    let mut available = vec![0u64; clients as usize];
+    let mut deposits: Vec<Vec<(u32, u64)>> = vec![Vec::new(); clients as usize];
+    let mut tx = 0u32;
+    let mut out = String::from("type, client, tx, amount\n");
+    for _ in 0..txns_per_client {
+        for client in 1..=clients {
+            let index = client as usize - 1;
+            let roll = next() as f64 / (1u64 << 31) as f64;
+            if !deposits[index].is_empty() && roll < dispute_ratio {
+                let pick = next() as usize % deposits[index].len();
+                let (disputed, cents) = deposits[index].swap_remove(pick);
+                if cents <= available[index] {
+                    available[index] -= cents;
+                    out.push_str(&format!("dispute, {}, {},\n", client, disputed));
+                    continue;
+                }
+            }
+            tx += 1;
+            if available[index] > 0 && next() % 10 < 3 {
+                let cents = next() % available[index] + 1;
+                available[index] -= cents;
+                out.push_str(&format!("withdrawal, {}, {}, {}.{:02}\n", client, tx, cents / 100, cents % 100));
+            } else {
+                let cents = next() % 10_000 + 1;
+                available[index] += cents;
+                deposits[index].push((tx, cents));
+                out.push_str(&format!("deposit, {}, {}, {}.{:02}\n", client, tx, cents / 100, cents % 100));
+            }
+        }
+    }
+    out
+}
+
+mod generate_csv_tests {
+    use rust_decimal::dec;
+    use crate::config::ProcessorConfig;
+    use crate::Processor;
+    use super::generate_csv;
+
+    #[test]
+    fn test_generated_csv_processes_cleanly() {
+        let data = generate_csv(20, 200, 0.1);
+        assert_eq!(data.lines().count(), 20 * 200 + 1);
+        assert!(data.contains("dispute"));
+        let config = ProcessorConfig {
+            strict_invariants: true,
+            ..Default::default()
+        };
+        let processor = Processor::process_str(&data, config).unwrap();
+        assert_eq!(processor.account_count(), 20);
+        assert!(processor.errors().is_empty());
+        assert!(processor.invariant_violations().is_empty());
+        assert!(processor.verify_against_history().unwrap().is_empty());
+        for (_, account) in processor.sorted_accounts() {
+            assert!(account.available() >= dec!(0));
+        }
+    }
+
+    #[test]
+    fn test_generated_csv_is_deterministic() {
+        assert_eq!(generate_csv(3, 10, 0.5), generate_csv(3, 10, 0.5));
+        assert_eq!(generate_csv(0, 10, 0.5), "type, client, tx, amount\n");
+    }
+}
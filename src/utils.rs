@@ -1,30 +1,869 @@
-use std::{env};
+use std::{env, fs};
 use std::error::Error;
 use std::ffi::OsString;
-use std::io::{stdout, Write};
+use std::io::{self, stdout, BufRead, ErrorKind, Write};
+use std::path::Path;
+#[cfg(feature = "glob")]
+use std::path::PathBuf;
+use std::str::FromStr;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use rust_decimal::{dec, Decimal};
+use crate::config::{ErrorSink, ProcessorConfig};
+use crate::model::{AccountDelta, AccountKey, AccountReport, TxEvent, TxId, TxKindRaw, TxRow};
 use crate::processor::Processor;
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortKey {
+    #[default]
+    Client,
+    Available,
+    Total
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BoolFormat {
+    #[default]
+    TrueFalse,
+    OneZero,
+    YesNo
+}
+
+impl BoolFormat {
+    pub fn render(&self, val: bool) -> &'static str {
+        match (self, val) {
+            (BoolFormat::TrueFalse, true) => "true",
+            (BoolFormat::TrueFalse, false) => "false",
+            (BoolFormat::OneZero, true) => "1",
+            (BoolFormat::OneZero, false) => "0",
+            (BoolFormat::YesNo, true) => "Y",
+            (BoolFormat::YesNo, false) => "N"
+        }
+    }
+}
+
+/// How the report is laid out: comma separated, or padded columns for reading in a terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Table
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<OutputFormat, String> {
+        match name {
+            "csv" => Ok(OutputFormat::Csv),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Unknown output format {:?}; expected csv or table", name))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputOptions {
+    pub sort_key: SortKey,
+    pub bool_format: BoolFormat,
+    /// Print balances in their shortest form (`1.5` rather than `1.5000`).
+    pub trim_trailing_zeros: bool,
+    /// Appends a totals block after the account rows.
+    pub summary: bool,
+    /// Print negative held, left by withdrawal disputes, as a positive `owed` column next to a
+    /// nonnegative `held`.
+    pub split_owed: bool,
+    /// Flush the writer after every this many account rows. `print_account_data` then streams to
+    /// stdout instead of rendering the whole report first.
+    pub flush_every: Option<usize>,
+    pub format: OutputFormat,
+    /// Write `Processor::trace`, one row per applied event, instead of the final balances.
+    pub trace: bool
+}
+
+pub struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool { metadata.level() <= log::max_level() }
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+    fn flush(&self) {}
+}
+
+static STDERR_LOGGER: StderrLogger = StderrLogger;
+
+/// Diagnostics only reach stderr when asked for: `--verbose` prints warnings and errors, and
+/// `RUST_LOG` (`error`, `warn`, `info`, `debug`, `trace` or `off`) picks any other level.
+pub fn stderr_log_level() -> Option<LevelFilter> {
+    match env::var("RUST_LOG").ok().and_then(|level| level.parse().ok()) {
+        Some(level) => Some(level),
+        None if has_flag("--verbose") => Some(LevelFilter::Warn),
+        None => None
+    }
+}
+
+pub fn init_stderr_logger(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&STDERR_LOGGER).map(|()| log::set_max_level(level))
+}
+
+/// Flags followed by a value, which is not mistaken for the input path.
+const VALUE_FLAGS: [&str; 3] = ["--output", "--format", "--explain"];
+
+/// The first argument that is neither a `--` flag nor a flag's value.
 pub fn get_first_arg() -> Result<OsString, Box<dyn Error>> {
-    match env::args_os().nth(1) {
-        None => Err(From::from("expected 1 argument, but got none")),
-        Some(file_path) => Ok(file_path)
-    }
-}
-
-pub fn print_account_data(processor: Processor) -> Result<(), Box<dyn Error>>{
-    let mut lock = stdout().lock();
-    writeln!(lock, "client, available, held, total, locked")?;
-    for account_data in processor.accounts() {
-        let account_key = account_data.key();
-        let account_string = format!("{:?}, {:?}, {:?}, {:?}, {:?}\n",
-                                     account_key,
-                                     account_data.available(),
-                                     account_data.held(),
-                                     account_data.total(),
-                                     account_data.locked()
-        );
-        write!(lock, "{}", account_string.as_str())?;
-    }
-    stdout().flush()?;
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let text = arg.to_string_lossy();
+        if VALUE_FLAGS.contains(&text.as_ref()) {
+            args.next();
+        } else if !text.starts_with("--") {
+            return Ok(arg);
+        }
+    }
+    Err(From::from("expected 1 argument, but got none"))
+}
+
+pub fn has_flag(flag: &str) -> bool {
+    env::args_os().skip(1).any(|arg| arg == flag)
+}
+
+pub fn flag_value(flag: &str) -> Option<OsString> {
+    let mut args = env::args_os().skip(1);
+    args.find(|arg| arg == flag)?;
+    args.next()
+}
+
+/// Expands a glob pattern such as `data/*.csv` into the matching files, sorted by name, for
+/// shells that leave a quoted pattern unexpanded. A path without wildcards is returned as is.
+#[cfg(feature = "glob")]
+pub fn expand_glob(pattern: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let text = pattern.to_str().ok_or_else(|| format!("{} is not valid UTF-8", pattern.display()))?;
+    if !text.contains(['*', '?', '[']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let mut paths = Vec::new();
+    for path in glob::glob(text)? {
+        let path = path?;
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        return Err(From::from(format!("no files match {}", pattern.display())));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Report rows ordered ascending by `sort_key`, ties broken by client id.
+pub fn account_report(processor: &Processor, sort_key: SortKey) -> Vec<AccountReport> {
+    let mut rows = processor.snapshot();
+    match sort_key {
+        SortKey::Client => {}
+        SortKey::Available => rows.sort_by_key(|row| row.available),
+        SortKey::Total => rows.sort_by_key(|row| row.total)
+    }
+    rows
+}
+
+/// An account's row from the serial and the chunked read; `None` where that read has no such account.
+pub type ModeDifference = (Option<AccountReport>, Option<AccountReport>);
+
+/// Rows that differ between a serial read of `data` and a read split into `chunks` parse chunks,
+/// ordered by client and currency; empty when the two agree. A guard against
+/// state leaking between chunks or clients.
+pub fn compare_read_modes(data: &[u8], chunks: usize) -> Result<Vec<ModeDifference>, Box<dyn Error>> {
+    let config = ProcessorConfig {
+        continue_on_error: true,
+        error_sink: ErrorSink::Silent,
+        ..Default::default()
+    };
+    let serial = Processor::process_reader(data, config.clone())?;
+    let chunked = Processor::process_reader(data, ProcessorConfig { parse_chunks: Some(chunks), ..config })?;
+    Ok(serial.diff(&chunked).into_iter().map(|diff| (diff.ours, diff.theirs)).collect())
+}
+
+/// Reads rows without a header from `input` one line at a time, applies each to `processor` and
+/// writes the resulting state of the account it acted on, or the row's error, to `output`.
+pub fn run_repl<R: BufRead, W: Write>(input: R, mut output: W, processor: &Processor) -> Result<(), Box<dyn Error>> {
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match apply_repl_line(&line, index as u64 + 1, processor) {
+            Ok(Some(key)) => {
+                let account = processor.accounts().get(&key).map(|account| account.clone()).unwrap_or_default();
+                let with_currency = key.1.is_some();
+                let cells = account_cells(AccountReport::new(&key, &account), with_currency, OutputOptions::default());
+                writeln!(output, "{}", cells.join(","))?;
+            }
+            Ok(None) => writeln!(output, "no matching account")?,
+            Err(err) => writeln!(output, "error: {}", err)?
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn apply_repl_line(line: &str, number: u64, processor: &Processor) -> Result<Option<AccountKey>, Box<dyn Error>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes());
+    let record = rdr.records().next().ok_or("empty row")??;
+    let row: TxRow = record.deserialize(None)?;
+    let mut ev = TxEvent::try_from_row(row, processor.config())?;
+    ev.line = Some(number);
+    let key = processor.account_key_of(&ev)?;
+    processor.apply(ev)?;
+    Ok(key)
+}
+
+/// Describes a deposit or withdrawal's lifecycle: the record, each event applied for its tx id with
+/// how it moved the account, where the dispute stands now and the account's current balances. Events
+/// are only listed when the processor ran with `record_deltas`.
+pub fn explain_tx(processor: &Processor, tx: TxId) -> Result<String, Box<dyn Error>> {
+    let record = processor.tx_history().get(tx)?.ok_or_else(|| format!("no deposit or withdrawal with tx {}", tx))?;
+    let kind = format!("{:?}", record.kind()).to_lowercase();
+    let mut out = format!("tx {}: {} of {} by client {}", tx, kind, record.amount(), record.client());
+    if let Some(to_client) = record.to_client() {
+        out.push_str(&format!(" to client {}", to_client));
+    }
+    if let Some(currency) = record.currency() {
+        out.push_str(&format!(" in {}", currency));
+    }
+    if let Some(line) = record.line() {
+        out.push_str(&format!(" (line {})", line));
+    }
+    out.push('\n');
+    let deltas: Vec<AccountDelta> = processor.deltas().into_iter().filter(|delta| delta.tx == tx).collect();
+    for delta in &deltas {
+        let kind = format!("{:?}", delta.kind).to_lowercase();
+        out.push_str(&format!("  {}: available {}, held {}", kind, signed(delta.available_delta), signed(delta.held_delta)));
+        if delta.locked && !delta.was_locked {
+            out.push_str(", locked the account");
+        }
+        out.push('\n');
+    }
+    let status = if *record.rejected() {
+        "never applied".to_string()
+    } else if *record.charged_back() {
+        "charged back".to_string()
+    } else if *record.disputed() {
+        format!("under dispute with {} held", record.disputed_amount())
+    } else if deltas.iter().any(|delta| delta.kind == TxKindRaw::Resolve) {
+        "resolved".to_string()
+    } else {
+        "not disputed".to_string()
+    };
+    out.push_str(&format!("status: {}\n", status));
+    let key = record.account_key();
+    let account = processor.accounts().get(&key).map(|account| account.clone()).unwrap_or_default();
+    out.push_str(&format!(
+        "client {}: available {}, held {}, total {}, locked {}\n",
+        key.0, amount_cell(account.available()), amount_cell(account.held()), amount_cell(account.total()), account.locked()
+    ));
+    Ok(out)
+}
+
+fn signed(delta: Decimal) -> String {
+    match delta.is_sign_negative() && !delta.is_zero() {
+        true => amount_cell(delta),
+        false => format!("+{}", amount_cell(delta))
+    }
+}
+
+/// Renders the whole report before writing it, so a failure part way through prints nothing, unless
+/// `flush_every` asks for rows as they are written.
+pub fn print_account_data(processor: Processor, options: OutputOptions) -> Result<(), Box<dyn Error>>{
+    emit_account_data(stdout().lock(), &processor, options)
+}
+
+/// Writes the report to an output stream, treating a reader that went away early (e.g. `| head`)
+/// as success rather than an error.
+fn emit_account_data<W: Write>(mut writer: W, processor: &Processor, options: OutputOptions) -> Result<(), Box<dyn Error>> {
+    let written = if options.flush_every.is_some() {
+        write_account_data(&mut writer, processor, options)
+    } else {
+        let mut out = Vec::new();
+        write_account_data(&mut out, processor, options)?;
+        writer.write_all(&out).map_err(From::from)
+    };
+    match written.and_then(|_| Ok(writer.flush()?)) {
+        Err(err) if is_broken_pipe(err.as_ref()) => Ok(()),
+        result => result
+    }
+}
+
+fn is_broken_pipe(err: &(dyn Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>().is_some_and(|err| err.kind() == ErrorKind::BrokenPipe)
+}
+
+/// Writes the report to `path`, creating or truncating it.
+pub fn save_account_data(processor: &Processor, path: &Path, options: OutputOptions) -> Result<(), Box<dyn Error>> {
+    let mut out = Vec::new();
+    write_account_data(&mut out, processor, options)?;
+    fs::write(path, out)?;
+    Ok(())
+}
+
+/// Adds a currency column after client only when some account has an explicit currency, so
+/// single-currency files keep the original layout.
+pub fn write_account_data<W: Write>(writer: &mut W, processor: &Processor, options: OutputOptions) -> Result<(), Box<dyn Error>>{
+    if options.trace {
+        return write_trace(writer, processor, options);
+    }
+    let rows = account_report(processor, options.sort_key);
+    let with_currency = rows.iter().any(|row| row.currency.is_some());
+    let mut header = vec!["client"];
+    if with_currency {
+        header.push("currency");
+    }
+    header.extend(["available", "held"]);
+    if options.split_owed {
+        header.push("owed");
+    }
+    header.extend(["total", "locked"]);
+    let cells: Vec<Vec<String>> = rows.into_iter().map(|row| account_cells(row, with_currency, options)).collect();
+    write_rows(writer, &header, &cells, options)?;
+    if options.summary {
+        let summary = processor.totals_summary();
+        writeln!(writer)?;
+        // normalized, since the scale of a sum depends on the order accounts are visited
+        let totals = vec![
+            summary.clients.to_string(),
+            amount_cell(summary.available.normalize()),
+            amount_cell(summary.held.normalize()),
+            summary.locked.to_string()
+        ];
+        write_rows(writer, &["clients", "available", "held", "locked"], &[totals], options)?;
+    }
     Ok(())
 }
+
+fn write_trace<W: Write>(writer: &mut W, processor: &Processor, options: OutputOptions) -> Result<(), Box<dyn Error>> {
+    let trace = processor.trace();
+    let with_currency = trace.iter().any(|row| row.currency.is_some());
+    let mut header = vec!["tx", "type", "client"];
+    if with_currency {
+        header.push("currency");
+    }
+    header.extend(["available", "held"]);
+    let cells: Vec<Vec<String>> = trace.into_iter()
+        .map(|row| {
+            let mut cells = vec![row.tx.to_string(), format!("{:?}", row.kind).to_lowercase(), row.client.to_string()];
+            if with_currency {
+                cells.push(row.currency.unwrap_or_default());
+            }
+            cells.extend([amount_cell(row.available), amount_cell(row.held)]);
+            cells
+        })
+        .collect();
+    write_rows(writer, &header, &cells, options)
+}
+
+fn account_cells(mut row: AccountReport, with_currency: bool, options: OutputOptions) -> Vec<String> {
+    if options.trim_trailing_zeros {
+        row.available = row.available.normalize();
+        row.held = row.held.normalize();
+        row.total = row.total.normalize();
+    }
+    let mut cells = vec![format!("{:?}", row.client)];
+    if with_currency {
+        cells.push(row.currency.unwrap_or_default());
+    }
+    cells.push(amount_cell(row.available));
+    match (options.split_owed, row.held < dec!(0)) {
+        (false, _) => cells.push(amount_cell(row.held)),
+        (true, false) => cells.extend([amount_cell(row.held), "0".to_string()]),
+        (true, true) => cells.extend(["0".to_string(), format!("{:?}", -row.held)])
+    }
+    cells.push(amount_cell(row.total));
+    cells.push(options.bool_format.render(row.locked).to_string());
+    cells
+}
+
+/// Zero keeps its scale but loses any sign, since adding a negated zero amount leaves `-0`.
+fn amount_cell(value: Decimal) -> String {
+    match value.is_zero() {
+        true => format!("{:?}", value.abs()),
+        false => format!("{:?}", value)
+    }
+}
+
+/// Writes a header and its rows in `options.format`, flushing at the `flush_every` cadence.
+fn write_rows<W: Write>(writer: &mut W, header: &[&str], rows: &[Vec<String>], options: OutputOptions) -> Result<(), Box<dyn Error>> {
+    let line = |cells: Vec<&str>, widths: &[usize]| -> String {
+        match options.format {
+            OutputFormat::Csv => cells.join(","),
+            OutputFormat::Table => cells.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join(" | ")
+        }
+    };
+    let mut widths: Vec<usize> = header.iter().map(|name| name.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    writeln!(writer, "{}", line(header.to_vec(), &widths))?;
+    if options.format == OutputFormat::Table {
+        let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+        writeln!(writer, "{}", rule.join("-+-"))?;
+    }
+    for (index, row) in rows.iter().enumerate() {
+        writeln!(writer, "{}", line(row.iter().map(String::as_str).collect(), &widths))?;
+        if options.flush_every.is_some_and(|every| (index + 1) % every.max(1) == 0) {
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod report_tests {
+    use std::ffi::OsString;
+    use crate::model::ClientId;
+    use crate::processor::Processor;
+    use crate::utils::{account_report, SortKey};
+
+    fn clients_by(sort_key: SortKey) -> Vec<ClientId> {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_sort_accounts.csv")).unwrap();
+        account_report(&processor, sort_key).iter().map(|row| row.client).collect()
+    }
+
+    #[test]
+    fn test_sort_by_client() {
+        assert_eq!(clients_by(SortKey::Client), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_sort_by_available() {
+        assert_eq!(clients_by(SortKey::Available), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_sort_by_total() {
+        assert_eq!(clients_by(SortKey::Total), vec![2, 3, 1]);
+    }
+}
+
+#[cfg(test)]
+mod compare_read_modes_tests {
+    use std::fs;
+    use crate::testutil::generate_csv;
+    use crate::utils::compare_read_modes;
+
+    #[test]
+    fn test_read_modes_agree() {
+        let data = fs::read("src/transaction_test_data/test_currencies.csv").unwrap();
+        assert!(compare_read_modes(&data, 3).unwrap().is_empty());
+        let generated = generate_csv(25, 100, 0.1);
+        assert!(compare_read_modes(generated.as_bytes(), 4).unwrap().is_empty());
+    }
+}
+
+#[cfg(all(test, feature = "glob"))]
+mod glob_tests {
+    use std::fs;
+    use rust_decimal::dec;
+    use crate::config::ProcessorConfig;
+    use crate::processor::Processor;
+    use crate::utils::expand_glob;
+
+    #[test]
+    fn test_glob_processes_matching_files_in_order() {
+        let dir = std::env::temp_dir().join(format!("csv-txn-glob-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("day2.csv"), "type, client, tx, amount\nwithdrawal, 1, 2, 1.5\n").unwrap();
+        fs::write(dir.join("day1.csv"), "type, client, tx, amount\ndeposit, 1, 1, 2.0\n").unwrap();
+        fs::write(dir.join("notes.txt"), "deposit, 1, 3, 100.0\n").unwrap();
+        let paths = expand_glob(&dir.join("*.csv"));
+        let processed = paths.as_ref().ok().map(|paths| Processor::process_files(paths, ProcessorConfig::default()));
+        let missing = expand_glob(&dir.join("*.json"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(paths.unwrap(), vec![dir.join("day1.csv"), dir.join("day2.csv")]);
+        assert_eq!(processed.unwrap().unwrap().account(1).unwrap().available(), dec!(0.5));
+        assert!(missing.is_err());
+        assert_eq!(expand_glob(&dir.join("day1.csv")).unwrap(), vec![dir.join("day1.csv")]);
+    }
+}
+
+#[cfg(test)]
+mod explain_tests {
+    use crate::config::ProcessorConfig;
+    use crate::processor::Processor;
+    use crate::utils::explain_tx;
+
+    #[test]
+    fn test_explain_disputed_then_resolved() {
+        let config = ProcessorConfig {
+            record_deltas: true,
+            ..Default::default()
+        };
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndeposit, 1, 2, 2.0\ndispute, 1, 1,\nresolve, 1, 1,\n";
+        let processor = Processor::process_str(data, config).unwrap();
+        assert_eq!(explain_tx(&processor, 1).unwrap(), "\
+            tx 1: deposit of 5.0 by client 1 (line 2)\n  \
+            deposit: available +5.0, held +0\n  \
+            dispute: available -5.0, held +5.0\n  \
+            resolve: available +5.0, held -5.0\n\
+            status: resolved\n\
+            client 1: available 7.0, held 0.0, total 7.0, locked false\n");
+        assert!(explain_tx(&processor, 3).is_err());
+    }
+}
+
+#[cfg(test)]
+mod repl_tests {
+    use rust_decimal::dec;
+    use crate::processor::Processor;
+    use crate::utils::run_repl;
+
+    #[test]
+    fn test_repl_echoes_account_state() {
+        let script = "deposit, 1, 1, 5.0\ndeposit, 2, 2, 1.0\n\ndispute, 1, 1\nresolve, 1, 1,\ndeposit, 1, 3, 2.0, EUR\nrefund, 1, 4\ndispute, 1, 99\n";
+        let processor = Processor::default();
+        let mut out = Vec::new();
+        run_repl(script.as_bytes(), &mut out, &processor).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..5], [
+            "1,5.0,0,5.0,false",
+            "2,1.0,0,1.0,false",
+            "1,0.0,5.0,5.0,false",
+            "1,5.0,0.0,5.0,false",
+            "1,EUR,2.0,0,2.0,false",
+        ]);
+        assert!(lines[5].starts_with("error: "), "{}", lines[5]);
+        assert_eq!(lines[6], "no matching account");
+        assert_eq!(lines.len(), 7);
+        assert_eq!(processor.account(1).unwrap().available(), dec!(5.0));
+    }
+}
+
+#[cfg(test)]
+mod output_tests {
+    use std::ffi::OsString;
+    use std::io::{self, ErrorKind, Write};
+    use crate::config::ProcessorConfig;
+    use crate::processor::Processor;
+    use crate::utils::{emit_account_data, save_account_data, write_account_data, BoolFormat, OutputFormat, OutputOptions};
+
+    fn render(bool_format: BoolFormat) -> String {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_data_early_lock.csv")).unwrap();
+        let options = OutputOptions {
+            bool_format,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_true_false_format() {
+        assert_eq!(render(BoolFormat::TrueFalse), "client,available,held,total,locked\n1,2.0,0.0,2.0,true\n2,2.0,0,2.0,false\n");
+    }
+
+    #[test]
+    fn test_exact_bytes() {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_base_data.csv")).unwrap();
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, OutputOptions::default()).unwrap();
+        let mut lines = out.split_inclusive(|b| *b == b'\n');
+        assert_eq!(lines.next(), Some(&b"client,available,held,total,locked\n"[..]));
+        assert_eq!(lines.next(), Some(&b"1,1.5,0,1.5,false\n"[..]));
+        assert!(!out.windows(2).any(|pair| pair == b", "));
+    }
+
+    #[test]
+    fn test_one_zero_format() {
+        assert_eq!(render(BoolFormat::OneZero), "client,available,held,total,locked\n1,2.0,0.0,2.0,1\n2,2.0,0,2.0,0\n");
+    }
+
+    #[test]
+    fn test_yes_no_format() {
+        assert_eq!(render(BoolFormat::YesNo), "client,available,held,total,locked\n1,2.0,0.0,2.0,Y\n2,2.0,0,2.0,N\n");
+    }
+
+    fn render_file(path: &str) -> String {
+        let processor = Processor::process_file(OsString::from(path)).unwrap();
+        assert_eq!(processor.accounts().len(), 0);
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, OutputOptions::default()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_empty_file_outputs_header_only() {
+        assert_eq!(render_file("src/transaction_test_data/test_empty.csv"), "client,available,held,total,locked\n");
+    }
+
+    #[test]
+    fn test_header_only_file_outputs_header_only() {
+        assert_eq!(render_file("src/transaction_test_data/test_header_only.csv"), "client,available,held,total,locked\n");
+    }
+
+    fn render_trailing_zeros(trim_trailing_zeros: bool) -> String {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_trailing_zeros.csv")).unwrap();
+        let options = OutputOptions {
+            trim_trailing_zeros,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_keeps_trailing_zeros_by_default() {
+        assert_eq!(render_trailing_zeros(false), "client,available,held,total,locked\n\
+            1,1.5000,0,1.5000,false\n\
+            2,0.0000,2.2500,2.2500,false\n");
+    }
+
+    #[test]
+    fn test_trim_trailing_zeros() {
+        assert_eq!(render_trailing_zeros(true), "client,available,held,total,locked\n\
+            1,1.5,0,1.5,false\n\
+            2,0,2.25,2.25,false\n");
+    }
+
+    fn render_bad_rows(atomic: bool) -> (bool, Vec<u8>) {
+        let config = ProcessorConfig {
+            continue_on_error: true,
+            atomic,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        let result = Processor::process_file_with_config(OsString::from("src/transaction_test_data/test_bad_rows.csv"), config)
+            .and_then(|processor| write_account_data(&mut out, &processor, OutputOptions::default()));
+        (result.is_ok(), out)
+    }
+
+    #[test]
+    fn test_atomic_mid_file_error_writes_nothing() {
+        let (ok, out) = render_bad_rows(true);
+        assert!(!ok);
+        assert!(out.is_empty());
+        let (ok, out) = render_bad_rows(false);
+        assert!(ok);
+        assert!(!out.is_empty());
+    }
+
+    #[test]
+    fn test_save_account_data() {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_data_early_lock.csv")).unwrap();
+        let path = std::env::temp_dir().join(format!("csv-txn-output-{}.csv", std::process::id()));
+        std::fs::write(&path, "stale contents that are longer than the report itself will be\n".repeat(4)).unwrap();
+        save_account_data(&processor, &path, OutputOptions::default()).unwrap();
+        let written = std::fs::read_to_string(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.unwrap(), "client,available,held,total,locked\n1,2.0,0.0,2.0,true\n2,2.0,0,2.0,false\n");
+    }
+
+    fn render_split_owed(path: &str) -> String {
+        let processor = Processor::process_file(OsString::from(path)).unwrap();
+        let options = OutputOptions {
+            split_owed: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_split_owed_on_withdrawal_dispute() {
+        assert_eq!(render_split_owed("src/transaction_test_data/test_dispute_withdrawal.csv"), "client,available,held,owed,total,locked\n\
+            1,3.0,0,1.5,1.5,false\n");
+    }
+
+    #[test]
+    fn test_split_owed_on_deposit_dispute() {
+        assert_eq!(render_split_owed("src/transaction_test_data/test_trailing_zeros.csv"), "client,available,held,owed,total,locked\n\
+            1,1.5000,0,0,1.5000,false\n\
+            2,0.0000,2.2500,0,2.2500,false\n");
+    }
+
+    #[test]
+    fn test_summary_appended() {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_data_early_lock.csv")).unwrap();
+        let options = OutputOptions {
+            summary: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, options).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "client,available,held,total,locked\n\
+            1,2.0,0.0,2.0,true\n\
+            2,2.0,0,2.0,false\n\
+            \n\
+            clients,available,held,locked\n\
+            2,4,0,1\n");
+    }
+
+    struct FlushCounter {
+        flushes: usize
+    }
+
+    impl Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    fn count_flushes(flush_every: Option<usize>) -> usize {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_sort_accounts.csv")).unwrap();
+        let options = OutputOptions {
+            flush_every,
+            ..Default::default()
+        };
+        let mut counter = FlushCounter { flushes: 0 };
+        write_account_data(&mut counter, &processor, options).unwrap();
+        counter.flushes
+    }
+
+    #[test]
+    fn test_flush_every() {
+        assert_eq!(count_flushes(None), 0);
+        assert_eq!(count_flushes(Some(1)), 3);
+        assert_eq!(count_flushes(Some(2)), 1);
+    }
+
+    #[test]
+    fn test_trace_output() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1,\nresolve, 1, 1,\n";
+        let config = ProcessorConfig {
+            trace: true,
+            ..Default::default()
+        };
+        let processor = Processor::process_str(data, config).unwrap();
+        let options = OutputOptions {
+            trace: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, options).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "tx,type,client,available,held\n\
+            1,deposit,1,5.0,0\n\
+            1,dispute,1,0.0,5.0\n\
+            1,resolve,1,5.0,0.0\n");
+    }
+
+    #[test]
+    fn test_negative_zero_is_written_unsigned() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 0.0\ndispute, 1, 1,\nresolve, 1, 1,\n";
+        let processor = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        let account = processor.account(1).unwrap();
+        assert!(account.held().is_sign_negative() && account.held().is_zero());
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, OutputOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "client,available,held,total,locked\n1,0,0,0,false\n");
+    }
+
+    struct ClosedPipe(ErrorKind);
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(self.0.into()) }
+        fn flush(&mut self) -> io::Result<()> { Err(self.0.into()) }
+    }
+
+    #[test]
+    fn test_broken_pipe_is_not_an_error() {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_sort_accounts.csv")).unwrap();
+        for flush_every in [None, Some(1)] {
+            let options = OutputOptions {
+                flush_every,
+                ..Default::default()
+            };
+            assert!(emit_account_data(ClosedPipe(ErrorKind::BrokenPipe), &processor, options).is_ok());
+            assert!(emit_account_data(ClosedPipe(ErrorKind::PermissionDenied), &processor, options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_zero_balance_locked_account_is_written() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 1.0\ndispute, 1, 1,\nchargeback, 1, 1,\n";
+        let processor = Processor::process_str(data, ProcessorConfig::default()).unwrap();
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, OutputOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "client,available,held,total,locked\n1,0,0.0,0.0,true\n2,1.0,0,1.0,false\n");
+    }
+
+    #[test]
+    fn test_table_format() {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_data_early_lock.csv")).unwrap();
+        let options = OutputOptions {
+            format: OutputFormat::Table,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, options).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+client | available | held | total | locked
+-------+-----------+------+-------+-------
+     1 |       2.0 |  0.0 |   2.0 |   true
+     2 |       2.0 |    0 |   2.0 |  false
+");
+        assert_eq!("table".parse::<OutputFormat>(), Ok(OutputFormat::Table));
+        assert!("pretty".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_currency_column() {
+        let processor = Processor::process_file(OsString::from("src/transaction_test_data/test_currencies.csv")).unwrap();
+        let mut out = Vec::new();
+        write_account_data(&mut out, &processor, OutputOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "client,currency,available,held,total,locked\n\
+            1,EUR,3.0,0,3.0,false\n\
+            1,USD,0.5,1.0,1.5,false\n");
+    }
+}
+
+#[cfg(test)]
+mod output_benchmarks {
+    use std::hint::black_box;
+    use std::io::Write;
+    use std::time::Instant;
+    use rust_decimal::Decimal;
+    use crate::model::Account;
+
+    // cargo test --release output_benchmarks -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_report_fields_vs_getters() {
+        let accounts: Vec<Account> = (0..1_000_000i64)
+            .map(|i| Account::from_parts(Decimal::new(i, 4), Decimal::new(i % 97, 2), i % 13 == 0))
+            .collect();
+        let mut out = Vec::with_capacity(64 * accounts.len());
+
+        let started = Instant::now();
+        for account in &accounts {
+            writeln!(out, "{:?}, {:?}, {:?}, {:?}", account.available(), account.held(), account.total(), account.locked()).unwrap();
+        }
+        let getters = started.elapsed();
+        black_box(&out);
+        out.clear();
+
+        let started = Instant::now();
+        for account in &accounts {
+            let (available, held, total, locked) = account.report_fields();
+            writeln!(out, "{:?}, {:?}, {:?}, {:?}", available, held, total, locked).unwrap();
+        }
+        let report_fields = started.elapsed();
+        black_box(&out);
+
+        println!("getters: {:?}, report_fields: {:?}", getters, report_fields);
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const FIXTURES: [&str; 7] = [
+    "test_base_data",
+    "test_locked",
+    "test_data_dispute_resolve",
+    "test_data_early_lock",
+    "test_dispute_withdrawal_chargeback",
+    "test_currencies",
+    "test_empty",
+];
+
+fn run(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_csv-txn-parser"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .unwrap();
+    (output.status.success(), String::from_utf8(output.stdout).unwrap())
+}
+
+fn golden(name: &str) -> String {
+    fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name)).unwrap()
+}
+
+#[test]
+fn test_fixtures_match_golden_output() {
+    for fixture in FIXTURES {
+        let (ok, stdout) = run(&[&format!("src/transaction_test_data/{}.csv", fixture)]);
+        assert!(ok, "{} failed", fixture);
+        assert_eq!(stdout, golden(&format!("{}.out", fixture)), "{} output changed", fixture);
+    }
+}
+
+#[test]
+fn test_summary_matches_golden_output() {
+    let (ok, stdout) = run(&["--summary", "src/transaction_test_data/test_locked.csv"]);
+    assert!(ok);
+    assert_eq!(stdout, golden("test_locked.summary.out"));
+}
+
+#[test]
+fn test_missing_file_prints_nothing() {
+    let (ok, stdout) = run(&["src/transaction_test_data/no_file_found.csv"]);
+    assert!(!ok);
+    assert!(stdout.is_empty());
+}
+
+#[test]
+fn test_warnings_only_with_verbose() {
+    let stderr = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_csv-txn-parser"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .env_remove("RUST_LOG")
+            .args(args)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stderr).unwrap()
+    };
+    assert_eq!(stderr(&["src/transaction_test_data/test_over_withdrawal.csv"]), "");
+    assert!(stderr(&["--verbose", "src/transaction_test_data/test_over_withdrawal.csv"])
+        .contains("WARN: Skipping withdrawal tx 4 for client 1: account locked or insufficient funds"));
+}